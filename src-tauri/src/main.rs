@@ -2,55 +2,310 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod plugin_manager;
-use plugin_manager::PluginManager;
-use lazy_static::lazy_static;
-use tokio::sync::Mutex;
+use async_trait::async_trait;
+use plugin_manager::{ExecutionEvent, ExecutionResult, Executions, HostApi, HostCaller, HostHandler, PluginError, PluginManager, PluginPermissions, PluginSourceKind, ResourceLimits, ScriptRuntime};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{Manager, RunEvent, State};
+use tokio::sync::{oneshot, RwLock};
 
-lazy_static! {
-    static ref PLUGIN_MANAGER: Mutex<PluginManager> = Mutex::new(PluginManager::new());
-}
+type PluginManagerState = RwLock<PluginManager>;
 
 fn main() {
-    tauri::Builder::default()
+    let app = tauri::Builder::default()
+        .setup(|app| {
+            let plugins_dir = match app.path_resolver().app_data_dir() {
+                Some(data_dir) => data_dir.join("plugins"),
+                None => return Err("Could not resolve the app data directory.".into()),
+            };
+            let config_dir = match app.path_resolver().app_config_dir() {
+                Some(config_dir) => config_dir,
+                None => return Err("Could not resolve the app config directory.".into()),
+            };
+
+            let mut plugin_manager = PluginManager::new(plugins_dir, &config_dir);
+            tauri::async_runtime::block_on(plugin_manager.load());
+            app.manage::<PluginManagerState>(RwLock::new(plugin_manager));
+
+            // Forward the output of plugin scripts to the frontend as it comes.
+            let app_handle = app.handle();
+            let executions = Executions::new(Some(Arc::new(move |event: ExecutionEvent| {
+                if let Err(err) = app_handle.emit_all(event.name(), &event) {
+                    println!("Error emitting {}: {}", event.name(), err);
+                }
+            })));
+            let host_api = tauri::async_runtime::block_on(HostApi::start(&config_dir, Box::new(AppHost {
+                app_handle: app.handle(),
+            })))?;
+            let app_version = app.package_info().version.to_string();
+            app.manage(ScriptRuntime::new(executions, host_api, ResourceLimits::load_defaults(&config_dir), app_version));
+
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
+            list_plugins,
             import_plugins,
+            list_available_plugins,
+            install_plugin,
+            uninstall_plugin,
+            check_plugin_updates,
+            update_plugin,
+            get_missing_plugin_permissions,
+            grant_plugin_permissions,
+            get_untrusted_shell_scripts,
+            trust_shell_scripts,
+            list_plugin_sources,
+            add_plugin_source,
+            remove_plugin_source,
+            reorder_plugin_sources,
+            set_plugin_source_enabled,
             execute_startup_script,
-            execute_function_script
+            execute_function_script,
+            list_functions,
+            execute_function,
+            wait_for_execution,
+            cancel_execution
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application");
+
+    app.run(|app_handle, event| {
+        if let RunEvent::Exit = event {
+            // Plugin scripts and daemons would otherwise outlive the app.
+            tauri::async_runtime::block_on(app_handle.state::<ScriptRuntime>().shutdown());
+        }
+    });
 }
 
 #[tauri::command]
-async fn import_plugins(app_handle: tauri::AppHandle) -> Result<String, String> {
-    let plugins_dir = app_handle
-        .path_resolver()
-        .app_data_dir()
-        .unwrap()
-        .join("plugins");
-    let mut plugin_manager = PLUGIN_MANAGER.lock().await;
-    plugin_manager.import_plugins(&plugins_dir).await;
+async fn list_plugins(plugin_manager: State<'_, PluginManagerState>) -> Result<String, PluginError> {
+    let plugin_manager = plugin_manager.read().await;
 
     let serialized_plugins = serde_json::to_string(&plugin_manager.plugins).unwrap();
     Ok(serialized_plugins)
 }
 
 #[tauri::command]
-async fn execute_startup_script(plugin_id: &str) -> Result<String, String> {
-    let plugin_manager = PLUGIN_MANAGER.lock().await;
-    let result = plugin_manager.execute_startup_script_from_id(&plugin_id).await;
-    match result {
-        Ok(result) => Ok(result.into()),
-        Err(err) => Err(err.to_string().into()),
-    }
+async fn import_plugins(plugin_manager: State<'_, PluginManagerState>) -> Result<String, PluginError> {
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.import_plugins().await;
+
+    let serialized_plugins = serde_json::to_string(&plugin_manager.plugins).unwrap();
+    Ok(serialized_plugins)
+}
+
+#[tauri::command]
+async fn list_available_plugins(plugin_manager: State<'_, PluginManagerState>) -> Result<String, PluginError> {
+    let fetcher = plugin_manager.read().await.fetcher();
+    let catalog = fetcher.fetch_catalog().await;
+
+    let serialized_catalog = serde_json::to_string(&catalog).unwrap();
+    Ok(serialized_catalog)
+}
+
+#[tauri::command]
+async fn install_plugin(plugin_manager: State<'_, PluginManagerState>, source: &str, plugin_id: &str) -> Result<String, PluginError> {
+    // Only the install itself needs the write lock, so nothing waits on the download.
+    let fetcher = plugin_manager.read().await.fetcher();
+    let downloaded = fetcher.download_plugin(source, plugin_id).await?;
+
+    let mut plugin_manager = plugin_manager.write().await;
+    let plugin = plugin_manager.install_plugin(downloaded).await?;
+    Ok(serde_json::to_string(plugin).unwrap())
+}
+
+#[tauri::command]
+async fn uninstall_plugin(plugin_manager: State<'_, PluginManagerState>, runtime: State<'_, ScriptRuntime>, plugin_id: &str) -> Result<(), PluginError> {
+    runtime.daemons.stop(plugin_id).await;
+
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.uninstall_plugin(plugin_id)
+}
+
+#[tauri::command]
+async fn check_plugin_updates(plugin_manager: State<'_, PluginManagerState>) -> Result<String, PluginError> {
+    let fetcher = plugin_manager.read().await.fetcher();
+    let catalog = fetcher.fetch_catalog().await;
+
+    let updates = plugin_manager.read().await.check_plugin_updates(&catalog);
+
+    let serialized_updates = serde_json::to_string(&updates).unwrap();
+    Ok(serialized_updates)
+}
+
+#[tauri::command]
+async fn update_plugin(plugin_manager: State<'_, PluginManagerState>, runtime: State<'_, ScriptRuntime>, plugin_id: &str) -> Result<String, PluginError> {
+    // Only the swap needs the write lock, so nothing waits on the download.
+    let fetcher = plugin_manager.read().await.fetcher();
+    let downloaded = fetcher.download_update(plugin_id).await?;
+
+    // The daemon runs from the plugin folder, which is swapped out during the update.
+    runtime.daemons.stop(plugin_id).await;
+
+    let mut plugin_manager = plugin_manager.write().await;
+    let plugin = plugin_manager.update_plugin(downloaded).await?;
+    Ok(serde_json::to_string(plugin).unwrap())
+}
+
+/// The permissions a plugin declares that the user has not approved yet. Empty once it can run.
+#[tauri::command]
+async fn get_missing_plugin_permissions(plugin_manager: State<'_, PluginManagerState>, plugin_id: &str) -> Result<String, PluginError> {
+    let plugin_manager = plugin_manager.read().await;
+    let missing = plugin_manager.missing_permissions(plugin_id)?;
+
+    Ok(serde_json::to_string(&missing).unwrap())
 }
 
+/// Approves the missing permissions of a plugin, as returned by `get_missing_plugin_permissions`. Only to be called once
+/// the user agreed to them.
 #[tauri::command]
-async fn execute_function_script(plugin_id: &str, script_path: &str, args: Vec<&str>) -> Result<String, String> {
-    let plugin_manager = PLUGIN_MANAGER.lock().await;
-    let result = plugin_manager.execute_function_script_from_id(&plugin_id, &script_path, Some(&args)).await;
-    match result {
-        Ok(result) => Ok(result.into()),
-        Err(err) => Err(err.to_string().into()),
+async fn grant_plugin_permissions(plugin_manager: State<'_, PluginManagerState>, plugin_id: &str, permissions: PluginPermissions) -> Result<(), PluginError> {
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.grant_permissions(plugin_id, &permissions)
+}
+
+/// The shell scripts of a plugin the user has not trusted yet, with their content. Empty once they can run.
+#[tauri::command]
+async fn get_untrusted_shell_scripts(plugin_manager: State<'_, PluginManagerState>, plugin_id: &str) -> Result<String, PluginError> {
+    let plugin_manager = plugin_manager.read().await;
+    let untrusted = plugin_manager.untrusted_shell_scripts(plugin_id)?;
+
+    Ok(serde_json::to_string(&untrusted).unwrap())
+}
+
+/// Trusts the shell scripts of a plugin with the given hashes. Only to be called once the user read and agreed to them.
+#[tauri::command]
+async fn trust_shell_scripts(plugin_manager: State<'_, PluginManagerState>, plugin_id: &str, hashes: Vec<String>) -> Result<(), PluginError> {
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.trust_shell_scripts(plugin_id, &hashes)
+}
+
+#[tauri::command]
+async fn list_plugin_sources(plugin_manager: State<'_, PluginManagerState>) -> Result<String, PluginError> {
+    let plugin_manager = plugin_manager.read().await;
+
+    let serialized_sources = serde_json::to_string(&plugin_manager.list_sources()).unwrap();
+    Ok(serialized_sources)
+}
+
+#[tauri::command]
+async fn add_plugin_source(plugin_manager: State<'_, PluginManagerState>, source: PluginSourceKind, trusted_keys: Option<Vec<String>>) -> Result<String, PluginError> {
+    // The source is checked before taking the write lock, so nothing waits on the requests to it.
+    let config = PluginManager::check_source(source, trusted_keys.unwrap_or_default()).await?;
+
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.add_source(config)
+}
+
+#[tauri::command]
+async fn remove_plugin_source(plugin_manager: State<'_, PluginManagerState>, source_id: &str) -> Result<(), PluginError> {
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.remove_source(source_id)
+}
+
+#[tauri::command]
+async fn reorder_plugin_sources(plugin_manager: State<'_, PluginManagerState>, source_ids: Vec<String>) -> Result<(), PluginError> {
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.reorder_sources(&source_ids)
+}
+
+#[tauri::command]
+async fn set_plugin_source_enabled(plugin_manager: State<'_, PluginManagerState>, source_id: &str, enabled: bool) -> Result<(), PluginError> {
+    let mut plugin_manager = plugin_manager.write().await;
+    plugin_manager.set_source_enabled(source_id, enabled)
+}
+
+/// Starts the startup script of a plugin and returns the id of its execution. `timeout` is in seconds.
+#[tauri::command]
+async fn execute_startup_script(plugin_manager: State<'_, PluginManagerState>, runtime: State<'_, ScriptRuntime>, plugin_id: &str, timeout: Option<u64>) -> Result<String, PluginError> {
+    // Only a snapshot of the plugin is needed to run it, so the lock is released right away.
+    let plugin = plugin_manager.read().await.get_runnable_plugin(plugin_id)?;
+    plugin.execute_startup_script(&runtime, timeout.map(Duration::from_secs))
+}
+
+/// Starts a function script of a plugin by its path and returns the id of its execution. `timeout` is in seconds.
+/// The path changes whenever the plugin is installed elsewhere, so `execute_function` is preferred.
+/// `args` can be any JSON, plugins using the `argv` calling convention get anything but strings as JSON text.
+#[tauri::command]
+async fn execute_function_script(plugin_manager: State<'_, PluginManagerState>, runtime: State<'_, ScriptRuntime>, plugin_id: &str, script_path: &str, args: Vec<Value>, timeout: Option<u64>) -> Result<String, PluginError> {
+    let plugin = plugin_manager.read().await.get_runnable_plugin(plugin_id)?;
+    plugin.execute_function_script_from_path(&runtime, script_path, &args, timeout.map(Duration::from_secs))
+}
+
+/// Every function of the installed plugins, with the ids to call them by.
+#[tauri::command]
+async fn list_functions(plugin_manager: State<'_, PluginManagerState>) -> Result<String, PluginError> {
+    let plugin_manager = plugin_manager.read().await;
+
+    let serialized_functions = serde_json::to_string(&plugin_manager.list_functions()).unwrap();
+    Ok(serialized_functions)
+}
+
+/// Starts a function by its full id, e.g. `3f1c...:summarize`, and returns the id of its execution. `timeout` is in
+/// seconds. Fails with `InvalidInput` if `args` don't match the function's parameters.
+#[tauri::command]
+async fn execute_function(plugin_manager: State<'_, PluginManagerState>, runtime: State<'_, ScriptRuntime>, function_id: &str, args: Vec<Value>, timeout: Option<u64>) -> Result<String, PluginError> {
+    let (plugin, function_id) = plugin_manager.read().await.resolve_function(function_id)?;
+    plugin.execute_function(&runtime, function_id, &args, timeout.map(Duration::from_secs))
+}
+
+#[tauri::command]
+async fn wait_for_execution(runtime: State<'_, ScriptRuntime>, execution_id: &str) -> Result<String, PluginError> {
+    let result = runtime.executions.wait(execution_id).await?;
+    Ok(serde_json::to_string(&result).unwrap())
+}
+
+#[tauri::command]
+async fn cancel_execution(runtime: State<'_, ScriptRuntime>, execution_id: &str) -> Result<(), PluginError> {
+    runtime.executions.cancel(execution_id)
+}
+
+/// Answers the host API calls of plugin scripts that need the app.
+struct AppHost {
+    app_handle: tauri::AppHandle,
+}
+
+#[async_trait]
+impl HostHandler for AppHost {
+    fn app_info(&self) -> Value {
+        let package_info = self.app_handle.package_info();
+        json!({ "name": package_info.name, "version": package_info.version.to_string() })
+    }
+
+    /**
+     * Notifications are shown by the frontend, which gets them as `plugin-notification` events.
+     */
+    async fn show_notification(&self, caller: &HostCaller, title: &str, body: &str) -> Result<(), PluginError> {
+        let notification = json!({
+            "plugin_id": caller.plugin_id,
+            "plugin_name": caller.plugin_name,
+            "title": title,
+            "body": body,
+        });
+
+        match self.app_handle.emit_all("plugin-notification", notification) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(PluginError::Io {
+                message: format!("Error showing the notification: {}", err),
+            }),
+        }
+    }
+
+    async fn ask(&self, caller: &HostCaller, question: &str) -> Result<bool, PluginError> {
+        let (sender, receiver) = oneshot::channel();
+        tauri::api::dialog::ask(None::<&tauri::Window>, &caller.plugin_name, question, move |answer| {
+            let _ = sender.send(answer);
+        });
+
+        Ok(receiver.await.unwrap_or(false))
+    }
+
+    async fn call_plugin(&self, caller: &HostCaller, plugin_id: &str, function: &str, args: Vec<Value>) -> Result<ExecutionResult, PluginError> {
+        let plugin = self.app_handle.state::<PluginManagerState>().read().await.get_callable_plugin(caller, plugin_id)?;
+
+        let runtime = self.app_handle.state::<ScriptRuntime>();
+        let execution_id = plugin.execute_function(&runtime, function, &args, None)?;
+        runtime.executions.wait(&execution_id).await
     }
 }
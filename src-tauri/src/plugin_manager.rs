@@ -2,52 +2,438 @@
 //! It can install, update, and remove plugins.
 //! It can also execute scripts from the plugins.
 
-use std::{fmt, fs, path};
+mod calling;
+mod daemon;
+mod error;
+mod execution;
+mod functions;
+mod host_api;
+mod install;
+mod limits;
+mod manifest;
+mod paths;
+mod permissions;
+mod registry;
+mod runtime;
+mod sandbox;
+mod signature;
+mod sources;
+
+use std::{fs, path};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
-use reqwest;
-
-#[derive(Debug)]
-pub struct PluginError {
-    message: String,
-}
-
-impl fmt::Display for PluginError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "PluginError: {}", self.message)
-    }
-}
+use serde_json::Value;
+use calling::{CallingConvention, InputEnvelope, InvocationContext};
+use execution::ProcessOptions;
+use functions::PluginFunction;
+use sandbox::Sandbox;
+
+pub use daemon::{DaemonCommand, Daemons};
+pub use error::PluginError;
+pub use execution::{ExecutionEvent, ExecutionResult, Executions};
+pub use limits::ResourceLimits;
+pub use host_api::{HostApi, HostCaller, HostHandler};
+pub use permissions::PluginPermissions;
+pub use runtime::ScriptRuntime;
+pub use registry::{RegistryIndex, RegistryPlugin, RegistryFile};
+pub use sources::{PluginSource, PluginSourceKind, PluginSourceConfig, GitHubSource, GiteaSource, GitLabSource, HttpIndexSource, LocalSource, RemoteEntry};
 
 pub struct PluginManager {
-    pub plugins: Vec<Box<Plugin>>,
-    pub sources: Vec<Box<PluginSource>>,
+    pub plugins: Vec<Arc<Plugin>>,
+    /// Where plugins are installed, one folder per plugin.
+    plugins_dir: path::PathBuf,
+    /// The enabled sources, in the order they are queried.
+    pub sources: Vec<Arc<dyn PluginSource>>,
+    /// Every configured source, enabled or not, as persisted in `sources.json`.
+    source_configs: Vec<PluginSourceConfig>,
+    /// Where the source configs are saved.
+    sources_path: path::PathBuf,
+    /// The permissions the user approved for each plugin, by plugin id, as persisted in `grants.json`.
+    grants: HashMap<String, PluginPermissions>,
+    /// Where the grants are saved.
+    grants_path: path::PathBuf,
+    /// The SHA-256 hashes of the shell scripts the user trusted, by plugin id, as persisted in `trusted_scripts.json`.
+    trusted_scripts: HashMap<String, Vec<String>>,
+    /// Where the trusted scripts are saved.
+    trusted_scripts_path: path::PathBuf,
 }
 
 impl PluginManager {
-    pub fn new() -> PluginManager {
-        let mut manager = PluginManager {
+    /**
+     * Creates an empty manager. Nothing is read from disk until `load` is called.
+     */
+    pub fn new(plugins_dir: path::PathBuf, config_dir: &path::Path) -> PluginManager {
+        PluginManager {
             plugins: Vec::new(),
+            plugins_dir,
             sources: Vec::new(),
+            source_configs: Vec::new(),
+            sources_path: config_dir.join("sources.json"),
+            grants: HashMap::new(),
+            grants_path: config_dir.join("grants.json"),
+            trusted_scripts: HashMap::new(),
+            trusted_scripts_path: config_dir.join("trusted_scripts.json"),
+        }
+    }
+
+    /**
+     * Loads the configured sources, the permission grants, the trusted scripts and the installed plugins.
+     * Called once when the app starts.
+     */
+    pub async fn load(&mut self) {
+        self.load_sources();
+        self.load_grants();
+        self.load_trusted_scripts();
+        self.import_plugins().await;
+    }
+
+    /**
+     * Loads the configured sources from `sources.json` in the config directory.
+     * The default sources are written there on first launch.
+     */
+    fn load_sources(&mut self) {
+        let source_configs = match fs::read_to_string(&self.sources_path) {
+            Ok(contents) => contents,
+            Err(_) => {
+                println!("No plugin sources configured, using the default sources.");
+                self.source_configs = Self::default_sources();
+                self.rebuild_sources();
+
+                if let Err(err) = self.save_sources() {
+                    println!("Error saving plugin sources: {}", err);
+                }
+                return;
+            }
+        };
+
+        self.source_configs = match serde_json::from_str(&source_configs) {
+            Ok(configs) => configs,
+            Err(err) => {
+                // The file is left untouched so that a typo made while editing it by hand doesn't wipe every source.
+                println!("Error parsing plugin sources, using the default sources: {}", err);
+                Self::default_sources()
+            }
+        };
+        self.rebuild_sources();
+    }
+
+    fn default_sources() -> Vec<PluginSourceConfig> {
+        let debug_source = GitHubSource::new(
+            Some("ZhichGaming".to_string()),
+            Some("Project-Raphael-Plugins".to_string()),
+            Some("debug".to_string()),
+        );
+
+        vec![PluginSourceConfig::new(PluginSourceKind::Github(debug_source), Vec::new())]
+    }
+
+    /**
+     * Recreates the enabled sources from the source configs.
+     */
+    fn rebuild_sources(&mut self) {
+        self.sources = self.source_configs
+            .iter()
+            .filter(|config| config.enabled)
+            .map(|config| Arc::from(config.kind.clone().into_source()))
+            .collect();
+    }
+
+    fn save_sources(&self) -> Result<(), PluginError> {
+        let sources_path = &self.sources_path;
+
+        if let Some(config_dir) = sources_path.parent() {
+            if let Err(err) = fs::create_dir_all(config_dir) {
+                return Err(PluginError::Io {
+                    message: format!("Error creating config directory {}: {}", config_dir.display(), err),
+                });
+            }
+        }
+
+        let serialized_configs = serde_json::to_string_pretty(&self.source_configs).unwrap();
+        if let Err(err) = fs::write(sources_path, serialized_configs) {
+            return Err(PluginError::Io {
+                message: format!("Error writing {}: {}", sources_path.display(), err),
+            });
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Loads the permissions approved for each plugin from `grants.json` in the config directory.
+     * Nothing is granted if the file is missing or cannot be parsed.
+     */
+    fn load_grants(&mut self) {
+        let grants = match fs::read_to_string(&self.grants_path) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+
+        self.grants = match serde_json::from_str(&grants) {
+            Ok(grants) => grants,
+            Err(err) => {
+                println!("Error parsing plugin permission grants, plugins will ask for their permissions again: {}", err);
+                HashMap::new()
+            }
+        };
+    }
+
+    fn save_grants(&self) -> Result<(), PluginError> {
+        let grants_path = &self.grants_path;
+
+        if let Some(config_dir) = grants_path.parent() {
+            if let Err(err) = fs::create_dir_all(config_dir) {
+                return Err(PluginError::Io {
+                    message: format!("Error creating config directory {}: {}", config_dir.display(), err),
+                });
+            }
+        }
+
+        let serialized_grants = serde_json::to_string_pretty(&self.grants).unwrap();
+        if let Err(err) = fs::write(grants_path, serialized_grants) {
+            return Err(PluginError::Io {
+                message: format!("Error writing {}: {}", grants_path.display(), err),
+            });
+        }
+
+        Ok(())
+    }
+
+    /**
+     * The permissions a plugin declares that the user has not approved yet.
+     */
+    pub fn missing_permissions(&self, plugin_id: &str) -> Result<PluginPermissions, PluginError> {
+        let plugin = self.get_plugin(plugin_id)?;
+        let granted = self.grants.get(plugin_id).cloned().unwrap_or_default();
+
+        Ok(plugin.permissions.missing_from(&granted))
+    }
+
+    /**
+     * Approves the permissions of a plugin the user agreed to, which must be the ones `missing_permissions` returns.
+     * Refuses if the plugin declares something else by now, e.g. because it was updated while the user was asked.
+     */
+    pub fn grant_permissions(&mut self, plugin_id: &str, approved: &PluginPermissions) -> Result<(), PluginError> {
+        if *approved != self.missing_permissions(plugin_id)? {
+            return Err(PluginError::Conflict {
+                message: format!("The permissions of plugin {} changed since they were approved. Approve them again.", plugin_id),
+            });
+        }
+
+        // Together with what was already granted, what was approved covers everything the plugin declares.
+        let plugin = self.get_plugin(plugin_id)?;
+        self.grants.insert(plugin_id.to_string(), plugin.permissions.clone());
+        self.save_grants()
+    }
+
+    /**
+     * Loads the hashes of the shell scripts the user trusted from `trusted_scripts.json` in the config directory.
+     * Nothing is trusted if the file is missing or cannot be parsed.
+     */
+    fn load_trusted_scripts(&mut self) {
+        let trusted_scripts = match fs::read_to_string(&self.trusted_scripts_path) {
+            Ok(contents) => contents,
+            Err(_) => return,
         };
 
-        let debug_source: PluginSource = PluginSource {
-            username: Some("ZhichGaming".to_string()),
-            repository: Some("Project-Raphael-Plugins".to_string()),
-            branch: Some("debug".to_string()),
+        self.trusted_scripts = match serde_json::from_str(&trusted_scripts) {
+            Ok(trusted_scripts) => trusted_scripts,
+            Err(err) => {
+                println!("Error parsing trusted shell scripts, plugins will ask for them to be trusted again: {}", err);
+                HashMap::new()
+            }
         };
+    }
+
+    fn save_trusted_scripts(&self) -> Result<(), PluginError> {
+        let trusted_scripts_path = &self.trusted_scripts_path;
+
+        if let Some(config_dir) = trusted_scripts_path.parent() {
+            if let Err(err) = fs::create_dir_all(config_dir) {
+                return Err(PluginError::Io {
+                    message: format!("Error creating config directory {}: {}", config_dir.display(), err),
+                });
+            }
+        }
+
+        let serialized_trusted_scripts = serde_json::to_string_pretty(&self.trusted_scripts).unwrap();
+        if let Err(err) = fs::write(trusted_scripts_path, serialized_trusted_scripts) {
+            return Err(PluginError::Io {
+                message: format!("Error writing {}: {}", trusted_scripts_path.display(), err),
+            });
+        }
+
+        Ok(())
+    }
+
+    /**
+     * The shell scripts of a plugin that the user has not trusted yet, with their content to show to the user.
+     * A script that changed since it was trusted, e.g. by an update, has to be trusted again.
+     */
+    pub fn untrusted_shell_scripts(&self, plugin_id: &str) -> Result<Vec<ShellScript>, PluginError> {
+        let plugin = self.get_plugin(plugin_id)?;
+        let trusted = self.trusted_scripts.get(plugin_id);
+
+        Ok(plugin
+            .shell_scripts()
+            .into_iter()
+            .filter(|script| !trusted.is_some_and(|trusted| trusted.contains(&script.hash)))
+            .collect())
+    }
+
+    /**
+     * Trusts the shell scripts of a plugin with the given hashes. Called once the user read and agreed to them.
+     * Only the hashes from `untrusted_shell_scripts` are accepted, so a script that changed in the meantime is not
+     * trusted without being shown.
+     */
+    pub fn trust_shell_scripts(&mut self, plugin_id: &str, hashes: &[String]) -> Result<(), PluginError> {
+        let plugin = self.get_plugin(plugin_id)?;
+        let scripts = plugin.shell_scripts();
+
+        if let Some(hash) = hashes.iter().find(|hash| !scripts.iter().any(|script| &script.hash == *hash)) {
+            return Err(PluginError::InvalidInput {
+                message: format!("Plugin {} has no shell script with hash {}.", plugin.name, hash),
+            });
+        }
+
+        // Hashes of scripts the plugin no longer has are dropped along the way.
+        let trusted = self.trusted_scripts.get(plugin_id).cloned().unwrap_or_default();
+        let trusted = scripts
+            .into_iter()
+            .map(|script| script.hash)
+            .filter(|hash| trusted.contains(hash) || hashes.contains(hash))
+            .collect();
+
+        self.trusted_scripts.insert(plugin_id.to_string(), trusted);
+        self.save_trusted_scripts()
+    }
+
+    /**
+     * Lists every configured source, enabled or not.
+     */
+    pub fn list_sources(&self) -> Vec<PluginSourceListing<'_>> {
+        self.source_configs
+            .iter()
+            .map(|config| PluginSourceListing {
+                id: config.id(),
+                config,
+            })
+            .collect()
+    }
+
+    /**
+     * Checks that a new source is reachable and actually hosts plugins, before it is added with `add_source`.
+     * It doesn't need the manager, so the requests to the source don't hold the lock on it.
+     */
+    pub async fn check_source(kind: PluginSourceKind, trusted_keys: Vec<String>) -> Result<PluginSourceConfig, PluginError> {
+        for trusted_key in &trusted_keys {
+            if let Err(err) = signature::parse_public_key(trusted_key) {
+                return Err(PluginError::InvalidInput {
+                    message: format!("Invalid trusted key {}: {}", trusted_key, err),
+                });
+            }
+        }
+
+        let config = PluginSourceConfig::new(kind, trusted_keys);
+
+        let source = config.kind.clone().into_source();
+        if source.fetch_index().await.is_none() {
+            if let Err(err) = source.list_folder("plugins").await {
+                return Err(PluginError::SourceInvalid {
+                    message: format!("Plugin source {} has neither a registry index nor a plugins folder: {}", config.id(), err),
+                });
+            }
+        }
+
+        Ok(config)
+    }
+
+    /**
+     * Adds a source checked with `check_source`. Returns the id of the new source.
+     */
+    pub fn add_source(&mut self, config: PluginSourceConfig) -> Result<String, PluginError> {
+        let source_id = config.id();
+
+        if self.source_configs.iter().any(|existing| existing.id() == source_id) {
+            return Err(PluginError::Conflict {
+                message: format!("Plugin source {} already exists.", source_id),
+            });
+        }
+
+        self.source_configs.push(config);
+        self.rebuild_sources();
+        self.save_sources()?;
 
-        manager.sources.push(Box::new(debug_source));
+        Ok(source_id)
+    }
+
+    pub fn remove_source(&mut self, source_id: &str) -> Result<(), PluginError> {
+        let index = self.find_source_config(source_id)?;
+
+        self.source_configs.remove(index);
+        self.rebuild_sources();
+        self.save_sources()
+    }
+
+    /**
+     * Reorders the sources. `source_ids` must contain every configured source exactly once.
+     */
+    pub fn reorder_sources(&mut self, source_ids: &[String]) -> Result<(), PluginError> {
+        let mut reordered: Vec<PluginSourceConfig> = Vec::new();
+
+        for source_id in source_ids {
+            let index = self.find_source_config(source_id)?;
+            if reordered.iter().any(|config| config.id() == *source_id) {
+                return Err(PluginError::InvalidInput {
+                    message: format!("Plugin source {} is listed more than once.", source_id),
+                });
+            }
+
+            reordered.push(self.source_configs[index].clone());
+        }
+
+        if reordered.len() != self.source_configs.len() {
+            return Err(PluginError::InvalidInput {
+                message: "Every plugin source must be listed when reordering.".to_string(),
+            });
+        }
+
+        self.source_configs = reordered;
+        self.rebuild_sources();
+        self.save_sources()
+    }
 
-        return manager;
+    pub fn set_source_enabled(&mut self, source_id: &str, enabled: bool) -> Result<(), PluginError> {
+        let index = self.find_source_config(source_id)?;
+
+        self.source_configs[index].enabled = enabled;
+        self.rebuild_sources();
+        self.save_sources()
+    }
+
+    fn find_source_config(&self, source_id: &str) -> Result<usize, PluginError> {
+        match self.source_configs.iter().position(|config| config.id() == source_id) {
+            Some(index) => Ok(index),
+            None => Err(PluginError::NotFound {
+                message: format!("Plugin source {} not found.", source_id),
+            }),
+        }
     }
 
-    pub async fn import_plugins(&mut self, plugins_dir: &path::PathBuf) {
+    /**
+     * Reimports every plugin from the plugins directory.
+     */
+    pub async fn import_plugins(&mut self) {
         self.plugins.clear();
-        self.import_plugins_from_local(plugins_dir).await;
-        self.import_plugins_from_remote(plugins_dir).await;
+        self.import_plugins_from_local(&self.plugins_dir.clone()).await;
     }
 
     pub async fn import_plugins_from_local(&mut self, plugins_dir: &path::PathBuf) {
         let plugins_dir = plugins_dir.as_path();
+        install::recover(plugins_dir);
+
         let plugin_files = match std::fs::read_dir(plugins_dir) {
             Ok(plugins) => plugins,
             Err(err) => {
@@ -78,7 +464,12 @@ impl PluginManager {
                 continue;
             }
 
-            let plugin = match self.import_plugin_from_local(unwrapped_plugin_path).await {
+            // Skip installations in progress
+            if plugin_path.file_name().and_then(|name| name.to_str()).is_some_and(install::is_transient) {
+                continue;
+            }
+
+            let plugin = match Self::import_plugin_from_local(unwrapped_plugin_path).await {
                 Ok(plugin) => plugin,
                 Err(err) => {
                     println!("Error importing plugin: {}", err);
@@ -86,33 +477,33 @@ impl PluginManager {
                 }
             };
 
-            self.plugins.push(Box::new(plugin));
+            self.plugins.push(Arc::new(plugin));
         }
     }
 
-    async fn import_plugin_from_local(&self, plugin_path: &str) -> Result<Plugin, PluginError> {
-        // Alright so would someone please explain to me why this works and returns the correct files but not when I unwrapped it safely? see commit `716e24f96284aefcf22e94716ff2be5454874d4b`
-        let plugin_script_dir_files: Vec<std::fs::DirEntry> = fs::read_dir(plugin_path.to_string() + "/scripts").unwrap().map(|res| res.unwrap()).collect();
-
-        // Import plugin info
-        let plugin_info = match std::fs::read_to_string(plugin_path.to_string() + "/info.json") {
-            Ok(plugin) => plugin,
+    async fn import_plugin_from_local(plugin_path: &str) -> Result<Plugin, PluginError> {
+        // Entries that cannot be read are skipped rather than failing the whole plugin.
+        let plugin_script_dir_files: Vec<std::fs::DirEntry> = match fs::read_dir(plugin_path.to_string() + "/scripts") {
+            Ok(entries) => entries.flatten().collect(),
             Err(err) => {
-                return Err(PluginError {
-                    message: format!("Error reading plugin: {}", err),
+                return Err(PluginError::Io {
+                    message: format!("Error reading the scripts of plugin {}: {}", plugin_path, err),
                 });
             }
         };
 
-        let plugin_info: Plugin = match serde_json::from_str(&plugin_info) {
+        // Import plugin info
+        let plugin_info = match std::fs::read_to_string(plugin_path.to_string() + "/info.json") {
             Ok(plugin) => plugin,
             Err(err) => {
-                return Err(PluginError {
-                    message: format!("Error parsing plugin: {}", err),
+                return Err(PluginError::Io {
+                    message: format!("Error reading plugin: {}", err),
                 });
             }
         };
 
+        let plugin_info = manifest::parse(&plugin_info)?;
+
         // Import startup script
         const STARTUP_SCRIPT_PREFIX: &str = "start";
 
@@ -132,13 +523,11 @@ impl PluginManager {
 
         let startup_script_filename = match startup_script_filename {
             Some(filename) => {
-                
-
                 let filename = match filename.file_name().to_str() {
                     Some(filename) => filename.to_string(),
                     None => {
-                        return Err(PluginError {
-                            message: format!("Error reading plugin: Encountered an error while getting {}", filename.file_name().to_str().unwrap()),
+                        return Err(PluginError::Io {
+                            message: format!("Error reading plugin: Encountered an error while getting {}", filename.file_name().to_string_lossy()),
                         });
                     }
                 };
@@ -158,7 +547,11 @@ impl PluginManager {
             Ok(script) => script,
             Err(_) => {
                 // Create an empty script at path.
-                std::fs::write(&startup_script_path, "").unwrap();
+                if let Err(err) = std::fs::write(&startup_script_path, "") {
+                    return Err(PluginError::Io {
+                        message: format!("Error creating startup script {}: {}", startup_script_path, err),
+                    });
+                }
 
                 "".to_string()
             }
@@ -171,13 +564,13 @@ impl PluginManager {
         };
 
         // Import function scripts
-        let function_scripts = plugin_script_dir_files.iter().filter_map(|entry| {
+        let function_scripts: Vec<PluginScript> = plugin_script_dir_files.iter().filter_map(|entry| {
             let entry = entry.file_name();
             let entry = match entry.to_str() {
                 Some(entry) => entry,
                 None => return None,
             };
-            
+
             if entry == ".DS_Store" {
                 return None;
             }
@@ -205,590 +598,1203 @@ impl PluginManager {
             return None;
         }).collect();
 
+        let (functions, function_scripts) = if plugin_info.functions.is_empty() {
+            let functions = function_scripts
+                .iter()
+                .filter_map(PluginScript::file_name)
+                .map(PluginFunction::from_entry)
+                .collect();
+
+            (functions, function_scripts)
+        } else {
+            // Daemons may handle functions without a script of their own.
+            if plugin_info.daemon.is_none() {
+                if let Some(function) = plugin_info.functions.iter().find(|function| !function_scripts.iter().any(|script| script.file_name() == Some(&function.entry))) {
+                    return Err(PluginError::ManifestInvalid {
+                        message: format!("The entry {} of function {} is not in the scripts folder.", function.entry, function.id),
+                    });
+                }
+            }
+
+            // Only the declared functions can be called, the other scripts are left to them.
+            let function_scripts = function_scripts
+                .into_iter()
+                .filter(|script| plugin_info.functions.iter().any(|function| script.file_name() == Some(&function.entry)))
+                .collect();
+
+            (plugin_info.functions, function_scripts)
+        };
+
         let plugin = Plugin {
             name: plugin_info.name,
             id: plugin_info.id,
-            version: plugin_info.version,
+            version: Some(plugin_info.version),
+            description: plugin_info.description,
             local_path: Some(plugin_path.to_string()),
-            remote_url: plugin_info.remote_url,
+            remote_url: None,
             startup_script: Some(startup_script),
             function_scripts: Some(function_scripts),
+            functions,
+            timeout: plugin_info.timeout,
+            daemon: plugin_info.daemon,
+            permissions: plugin_info.permissions,
+            limits: plugin_info.limits,
+            calling_convention: plugin_info.calling_convention,
         };
-        // println!("{:?}", plugin);
-
-        // match self.execute_startup_script(&plugin).await {
-        //     Ok(output) => {
-        //         println!("Startup script output: {}", output);
-        //     },
-        //     Err(err) => {
-        //         println!("Error executing startup script: {}", err);
-        //     }
-        // }
 
         Ok(plugin)
     }
 
-    pub async fn import_plugins_from_remote(&mut self, plugins_dir: &path::PathBuf) {
-        for source in &self.sources {
-            let folder_url = match source.get_folder_url("plugins") {
-                Ok(url) => url,
-                Err(err) => {
-                    println!("Error getting folder URL: {}", err);
-                    continue;
-                }
-            };
+    /**
+     * Takes what talking to the sources needs from the manager, so that it can happen without holding the lock on it.
+     */
+    pub fn fetcher(&self) -> PluginFetcher {
+        PluginFetcher {
+            sources: self.sources.clone(),
+            trusted_keys: self.source_configs.iter().map(|config| (config.id(), config.trusted_keys.clone())).collect(),
+            installed: self.plugins.iter().map(|plugin| (plugin.id.clone(), plugin.version.clone())).collect(),
+            plugins_dir: self.plugins_dir.clone(),
+        }
+    }
 
-            let folder_contents = match reqwest::Client::new().get(&folder_url).header("User-Agent", "Mozilla/5.0").send().await {
-                Ok(response) => response,
-                Err(err) => {
-                    println!("Error getting folder contents: {}", err);
-                    continue;
-                }
-            };
+    /**
+     * Installs a plugin downloaded with `PluginFetcher::download_plugin` in the plugins directory.
+     */
+    pub async fn install_plugin(&mut self, downloaded: DownloadedPlugin) -> Result<&Plugin, PluginError> {
+        // It may have been installed while it was downloading.
+        if self.plugins.iter().any(|plugin| plugin.id == downloaded.plugin_id) {
+            return Err(PluginError::Conflict {
+                message: format!("Plugin with id {} is already installed.", downloaded.plugin_id),
+            });
+        }
 
-            let folder_contents: String = match folder_contents.text().await {
-                Ok(contents) => contents,
-                Err(err) => {
-                    println!("Error parsing folder contents: {}", err);
-                    continue;
-                }
-            };
+        let local_plugin_path = self.plugins_dir.join(&downloaded.folder_name);
+        downloaded.staged.install(&local_plugin_path)?;
 
-            let folder_contents: Vec<serde_json::Value> = match serde_json::from_str(&folder_contents) {
-                Ok(contents) => contents,
-                Err(err) => {
-                    println!("Error parsing folder contents to JSON: {}", err);
-                    continue;
-                }
-            };
+        let mut plugin = Self::import_plugin_from_local(&path_to_string(&local_plugin_path)?).await?;
+        plugin.remote_url = Some(downloaded.info_url);
+        self.plugins.push(Arc::new(plugin));
 
-            for plugin_folder in folder_contents {
-                let plugin_folder = match plugin_folder.as_object() {
-                    Some(content) => content,
-                    None => {
-                        println!("Error parsing folder content.");
-                        continue;
-                    }
-                };
+        println!("Plugin {} has been installed.", downloaded.folder_name);
 
-                let plugin_folder_type = match plugin_folder.get("type") {
-                    Some(content_type) => content_type,
-                    None => {
-                        println!("Error getting folder content type.");
-                        continue;
-                    }
-                };
+        Ok(self.plugins.last().unwrap())
+    }
 
-                let plugin_folder_type = match plugin_folder_type.as_str() {
-                    Some(content_type) => content_type,
-                    None => {
-                        println!("Error parsing folder content type.");
-                        continue;
-                    }
-                };
+    /**
+     * Removes an installed plugin from the plugins directory.
+     */
+    pub fn uninstall_plugin(&mut self, plugin_id: &str) -> Result<(), PluginError> {
+        let index = match self.plugins.iter().position(|plugin| plugin.id == plugin_id) {
+            Some(index) => index,
+            None => {
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin with id {} not found.", plugin_id),
+                });
+            }
+        };
 
-                if plugin_folder_type != "dir" {
-                    continue;
-                }
+        if let Some(local_path) = &self.plugins[index].local_path {
+            if let Err(err) = fs::remove_dir_all(local_path) {
+                return Err(PluginError::Io {
+                    message: format!("Error removing plugin directory {}: {}", local_path, err),
+                });
+            }
+        }
 
-                let plugin_folder_name = match plugin_folder.get("name") {
-                    Some(content_name) => content_name,
-                    None => {
-                        println!("Error getting folder content name.");
-                        continue;
-                    }
-                };
+        let plugin = self.plugins.remove(index);
+        println!("Plugin {} has been uninstalled.", plugin.name);
 
-                let plugin_folder_name = match plugin_folder_name.as_str() {
-                    Some(content_name) => content_name,
-                    None => {
-                        println!("Error parsing folder content name.");
-                        continue;
-                    }
-                };
+        // A plugin installed again later asks for its permissions again.
+        if self.grants.remove(plugin_id).is_some() {
+            if let Err(err) = self.save_grants() {
+                println!("Error saving plugin permission grants: {}", err);
+            }
+        }
+
+        if self.trusted_scripts.remove(plugin_id).is_some() {
+            if let Err(err) = self.save_trusted_scripts() {
+                println!("Error saving trusted shell scripts: {}", err);
+            }
+        }
+
+        Ok(())
+    }
 
-                let local_plugin_path = plugins_dir.to_str().unwrap().to_string() + "/" + plugin_folder_name;
+    /**
+     * Compares the installed plugins against a catalog from `PluginFetcher::fetch_catalog` and lists the ones with a
+     * newer version available.
+     */
+    pub fn check_plugin_updates(&self, catalog: &[CatalogEntry]) -> Vec<PluginUpdate> {
+        let mut updates: Vec<PluginUpdate> = Vec::new();
 
-                // Check if this plugin is already installed
-                if std::path::Path::new(&local_plugin_path).exists() {
-                    println!("Plugin {} is already installed.", plugin_folder_name);
+        for plugin in &self.plugins {
+            let installed_version = match plugin.version.as_deref().and_then(parse_version) {
+                Some(version) => version,
+                None => {
+                    println!("Plugin {} does not have a valid semver version, skipping update check.", plugin.name);
                     continue;
                 }
+            };
 
-                let plugin_info_url = match source.get_file_url(&("plugins/".to_string() + plugin_folder_name + "/info.json")) {
-                    Ok(url) => url,
-                    Err(err) => {
-                        println!("Error getting plugin info URL: {}", err);
-                        continue;
-                    }
-                };
-
-                let plugin_info = match reqwest::get(&plugin_info_url).await {
-                    Ok(response) => response,
-                    Err(err) => {
-                        println!("Error getting plugin info: {}", err);
-                        continue;
-                    }
-                };
+            let (entry, available_version) = match newest_catalog_entry(catalog, &plugin.id) {
+                Some(newest) => newest,
+                None => continue,
+            };
 
-                let plugin_info = match plugin_info.text().await {
-                    Ok(info) => info,
-                    Err(err) => {
-                        println!("Error parsing plugin info: {}", err);
-                        continue;
-                    }
-                };
+            if available_version > installed_version {
+                updates.push(PluginUpdate {
+                    id: plugin.id.clone(),
+                    name: plugin.name.clone(),
+                    source: entry.source.clone(),
+                    installed_version: installed_version.to_string(),
+                    available_version: available_version.to_string(),
+                });
+            }
+        }
 
-                let parsed_plugin_info: Plugin = match serde_json::from_str(&plugin_info) {
-                    Ok(info) => info,
-                    Err(err) => {
-                        println!("Error parsing plugin info: {}", err);
-                        continue;
-                    }
-                };
+        updates
+    }
 
-                let plugin_scripts_url = match source.get_folder_url(&("plugins/".to_string() + plugin_folder_name + "/scripts")) {
-                    Ok(url) => url,
-                    Err(err) => {
-                        println!("Error getting plugin URL: {}", err);
-                        continue;
-                    }
-                };
-
-                let plugin_scripts = match reqwest::Client::new().get(&plugin_scripts_url).header("User-Agent", "Mozilla/5.0").send().await {
-                    Ok(response) => response,
-                    Err(err) => {
-                        println!("Error getting plugin scripts: {}", err);
-                        continue;
-                    }
-                };
-
-                let plugin_scripts: String = match plugin_scripts.text().await {
-                    Ok(contents) => contents,
-                    Err(err) => {
-                        println!("Error parsing plugin scripts: {}", err);
-                        continue;
-                    }
-                };
+    /**
+     * Replaces an installed plugin with a newer version downloaded with `PluginFetcher::download_update`.
+     * Everything in the plugin folder is replaced except the plugin's data folder, which is kept.
+     */
+    pub async fn update_plugin(&mut self, downloaded: DownloadedPlugin) -> Result<&Plugin, PluginError> {
+        let index = match self.plugins.iter().position(|plugin| plugin.id == downloaded.plugin_id) {
+            Some(index) => index,
+            None => {
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin with id {} not found.", downloaded.plugin_id),
+                });
+            }
+        };
 
-                let plugin_scripts: Vec<serde_json::Value> = match serde_json::from_str(&plugin_scripts) {
-                    Ok(contents) => contents,
-                    Err(err) => {
-                        println!("Error parsing plugin scripts to JSON: {}", err);
-                        continue;
-                    }
-                };
+        let local_plugin_path = match self.plugins[index].local_path {
+            Some(ref path) => path.clone(),
+            None => {
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} is not installed locally.", self.plugins[index].name),
+                });
+            }
+        };
 
-                let mut startup_script: Option<PluginScript> = None;
-
-                let mut function_scripts: Vec<PluginScript> = Vec::new();
-
-                for content in plugin_scripts.iter() {
-                    let content = match content.as_object() {
-                        Some(content) => content,
-                        None => {
-                            println!("Error parsing plugin script.");
-                            continue;
-                        }
-                    };
-
-                    let content_type = match content.get("type") {
-                        Some(content_type) => content_type,
-                        None => {
-                            println!("Error getting plugin script type.");
-                            continue;
-                        }
-                    };
-
-                    let content_type = match content_type.as_str() {
-                        Some(content_type) => content_type,
-                        None => {
-                            println!("Error parsing plugin script type.");
-                            continue;
-                        }
-                    };
-
-                    if content_type == "file" {
-                        let script_file_name = match content.get("name") {
-                            Some(content_name) => content_name,
-                            None => {
-                                println!("Error getting plugin script name.");
-                                continue;
-                            }
-                        };
-
-                        let script_file_name = match script_file_name.as_str() {
-                            Some(content_name) => content_name,
-                            None => {
-                                println!("Error parsing plugin script name.");
-                                continue;
-                            }
-                        };
-
-                        let script_url = match source.get_file_url(&("plugins/".to_string() + plugin_folder_name + "/scripts/" + script_file_name)) {
-                            Ok(url) => url,
-                            Err(err) => {
-                                println!("Error getting plugin script URL: {}", err);
-                                continue;
-                            }
-                        };
-
-                        let script = match reqwest::get(&script_url).await {
-                            Ok(response) => response,
-                            Err(err) => {
-                                println!("Error getting plugin script: {}", err);
-                                continue;
-                            }
-                        };
-
-                        let script = match script.text().await {
-                            Ok(script) => script,
-                            Err(err) => {
-                                println!("Error parsing plugin script: {}", err);
-                                continue;
-                            }
-                        };
-
-                        let script = PluginScript {
-                            path: Some(format!("{}{}{}", &local_plugin_path, "/scripts/", script_file_name)),
-                            script: Some(script),
-                            engine: Some(plugin_folder_name.split('.').last().unwrap().to_string()),
-                        };
-
-                        if script_file_name.starts_with("start") {
-                            startup_script = Some(script);
-                        } else {
-                            function_scripts.push(script);
-                        }
-                    }
-                }
+        // It may have been updated while the new version was downloading.
+        let installed_version = self.plugins[index].version.as_deref().and_then(parse_version);
+        if installed_version.is_some_and(|installed_version| Some(installed_version) >= parse_version(&downloaded.version)) {
+            return Err(PluginError::Conflict {
+                message: format!("Plugin {} is already up to date.", self.plugins[index].name),
+            });
+        }
 
-                let plugin = Plugin {
-                    name: parsed_plugin_info.name,
-                    id: parsed_plugin_info.id,
-                    version: parsed_plugin_info.version,
-                    local_path: Some(local_plugin_path),
-                    remote_url: Some(plugin_info_url),
-                    startup_script: startup_script.clone(),
-                    function_scripts: Some(function_scripts.to_vec()),
-                };
+        let replaced_plugin = downloaded.staged.replace(path::Path::new(&local_plugin_path))?;
 
-                // Save the plugin to the local directory
-                let plugin_dir = plugins_dir.to_str().unwrap().to_string() + "/" + plugin_folder_name;
-                std::fs::create_dir_all(&plugin_dir).unwrap();
+        let mut plugin = match Self::import_plugin_from_local(&local_plugin_path).await {
+            Ok(plugin) => plugin,
+            Err(err) => {
+                replaced_plugin.rollback();
+                return Err(err);
+            }
+        };
+        replaced_plugin.commit();
 
-                let plugin_info_path = plugin_dir.to_string() + "/info.json";
-                std::fs::write(&plugin_info_path, plugin_info).unwrap();
+        plugin.remote_url = Some(downloaded.info_url);
+        self.plugins[index] = Arc::new(plugin);
 
-                let plugin_scripts_dir = plugin_dir.to_string() + "/scripts";
-                std::fs::create_dir_all(&plugin_scripts_dir).unwrap();
+        println!("Plugin {} has been updated to {}.", self.plugins[index].name, downloaded.version);
 
-                for script in &function_scripts {
-                    let script_path = script.path.as_ref().unwrap();
-                    let script_path = script_path.split('/').last().unwrap();
-                    let script_path = plugin_scripts_dir.to_string() + "/" + script_path;
-                    std::fs::write(&script_path, script.script.as_ref().unwrap()).unwrap();
-                }
+        Ok(&self.plugins[index])
+    }
 
-                if !startup_script.is_none() {
-                    let startup_script = startup_script.as_ref().unwrap();
-                    let startup_script_path = startup_script.path.as_ref().unwrap();
-                    let startup_script_path = startup_script_path.split('/').last().unwrap();
-                    let startup_script_path = plugin_scripts_dir.to_string() + "/" + startup_script_path;
+    /**
+     * Gets a snapshot of an installed plugin. It stays usable after the lock on the manager is released,
+     * even if the plugin gets updated or uninstalled in the meantime.
+     */
+    pub fn get_plugin(&self, plugin_id: &str) -> Result<Arc<Plugin>, PluginError> {
+        match self.plugins.iter().find(|plugin| plugin.id == plugin_id) {
+            Some(plugin) => Ok(Arc::clone(plugin)),
+            None => Err(PluginError::NotFound {
+                message: format!("Plugin with id {} not found.", plugin_id),
+            }),
+        }
+    }
 
-                    std::fs::write(&startup_script_path, startup_script.script.as_ref().unwrap()).unwrap();
-                } else {
-                    let startup_script_path = plugin_scripts_dir.to_string() + "/start.js";
-                    std::fs::write(&startup_script_path, "").unwrap();
-                }
+    /**
+     * Lists the functions of every installed plugin, with the full ids to call them by.
+     */
+    pub fn list_functions(&self) -> Vec<FunctionListing<'_>> {
+        self.plugins
+            .iter()
+            .flat_map(|plugin| {
+                plugin.functions.iter().map(|function| FunctionListing {
+                    id: functions::qualified_id(&plugin.id, &function.id),
+                    plugin_id: &plugin.id,
+                    plugin_name: &plugin.name,
+                    function,
+                })
+            })
+            .collect()
+    }
 
-                self.plugins.push(Box::new(plugin));
+    /**
+     * Finds the plugin a function id like `3f1c...:summarize` refers to, as `get_runnable_plugin` does, along with
+     * the id of the function within the plugin.
+     */
+    pub fn resolve_function<'a>(&self, function_id: &'a str) -> Result<(Arc<Plugin>, &'a str), PluginError> {
+        let (plugin_id, function_id) = functions::split_qualified_id(function_id)?;
+        let plugin = self.get_runnable_plugin(plugin_id)?;
 
-                println!("Plugin {} has been installed.", plugin_folder_name);
-            }
-        }       
+        Ok((plugin, function_id))
     }
 
     /**
-     * Executes the startup script of the plugin from the plugin id.
+     * Gets a plugin to run one of its scripts, refusing if the user has not approved everything it declares or has not
+     * trusted all of its shell scripts.
      */
-    pub async fn execute_startup_script_from_id(&self, plugin_id: &str) -> Result<String, PluginError> {
-        let plugin = match self.plugins.iter().find(|plugin| plugin.id == plugin_id) {
-            Some(plugin) => plugin,
-            None => {
-                return Err(PluginError {
-                    message: format!("Plugin with id {} not found.", plugin_id),
-                });
-            }
-        };
+    pub fn get_runnable_plugin(&self, plugin_id: &str) -> Result<Arc<Plugin>, PluginError> {
+        let missing = self.missing_permissions(plugin_id)?;
 
-        self.execute_startup_script(&plugin).await
+        if !missing.is_empty() {
+            return Err(PluginError::PermissionsNotGranted {
+                plugin_id: plugin_id.to_string(),
+                missing: Box::new(missing),
+            });
+        }
+
+        let untrusted = self.untrusted_shell_scripts(plugin_id)?;
+
+        if !untrusted.is_empty() {
+            return Err(PluginError::ShellScriptsNotTrusted {
+                plugin_id: plugin_id.to_string(),
+                paths: untrusted.into_iter().map(|script| script.path).collect(),
+            });
+        }
+
+        self.get_plugin(plugin_id)
     }
 
     /**
-     * Executes the startup script of the plugin.
+     * Gets a plugin for another one to run one of its functions through the host API, as `get_runnable_plugin` does.
+     * The caller must be allowed to call it, and must have every permission it has, so that it can't use it to do
+     * what it isn't allowed to.
      */
-    async fn execute_startup_script(&self, plugin: &Plugin) -> Result<String, PluginError> {
-        if plugin.startup_script.is_none() {
-            return Err(PluginError {
-                message: format!("Plugin {} has no startup script.", plugin.name),
+    pub fn get_callable_plugin(&self, caller: &HostCaller, plugin_id: &str) -> Result<Arc<Plugin>, PluginError> {
+        if !caller.permissions.allows_calling_plugin(plugin_id) {
+            return Err(PluginError::PermissionDenied {
+                message: format!("Plugin {} does not have the permission to call plugin {}.", caller.plugin_name, plugin_id),
             });
         }
 
-        let script = plugin.startup_script.as_ref().unwrap();
-        let script_code = match script.script {
-            Some(ref script) => script,
-            None => {
-                return Err(PluginError {
-                    message: format!("Plugin {} has no startup script.", plugin.name),
-                });
-            }
-        };
-        let engine = match script.engine {
-            Some(ref engine) => engine,
-            None => {
-                return Err(PluginError {
-                    message: format!("Plugin {}'s startup script does not have a type.", plugin.name),
-                });
+        let plugin = self.get_runnable_plugin(plugin_id)?;
+
+        if !plugin.permissions.missing_from(&caller.permissions).is_empty() {
+            return Err(PluginError::PermissionDenied {
+                message: format!("Plugin {} cannot call plugin {}, which has permissions it does not have.", caller.plugin_name, plugin.name),
+            });
+        }
+
+        Ok(plugin)
+    }
+}
+
+/// The sources of the manager, detached from it so that talking to them doesn't hold the lock on the manager.
+/// Taken with `PluginManager::fetcher`.
+pub struct PluginFetcher {
+    sources: Vec<Arc<dyn PluginSource>>,
+    /// The trusted keys of every configured source, by source id.
+    trusted_keys: HashMap<String, Vec<String>>,
+    /// The versions of the installed plugins, by plugin id.
+    installed: HashMap<String, Option<String>>,
+    plugins_dir: path::PathBuf,
+}
+
+/// A plugin downloaded, verified and staged by a `PluginFetcher`, ready to be installed by the manager.
+/// The staged files are removed if it is dropped.
+pub struct DownloadedPlugin {
+    plugin_id: String,
+    folder_name: String,
+    version: String,
+    info_url: String,
+    staged: install::StagedPlugin,
+}
+
+impl PluginFetcher {
+    /**
+     * Lists the plugins available from every source without downloading them.
+     * Sources that fail to respond are skipped so that one broken source does not hide the others.
+     */
+    pub async fn fetch_catalog(&self) -> Vec<CatalogEntry> {
+        let mut catalog: Vec<CatalogEntry> = Vec::new();
+
+        for source in &self.sources {
+            match self.fetch_source_catalog(source.as_ref()).await {
+                Ok(entries) => catalog.extend(entries),
+                Err(err) => {
+                    println!("Error fetching catalog from source {}: {}", source.id(), err);
+                    continue;
+                }
             }
+        }
+
+        catalog
+    }
+
+    /**
+     * Lists the plugins available from a single source, using its registry index when it publishes one.
+     */
+    async fn fetch_source_catalog(&self, source: &dyn PluginSource) -> Result<Vec<CatalogEntry>, PluginError> {
+        let index = match source.fetch_index().await {
+            Some(index) => index,
+            None => return self.crawl_source_catalog(source).await,
         };
 
-        match engine.as_str() {
-            "py" => {
-                return self.execute_python_script(script_code, None).await;
-            }
-            "js" => {
-                return self.execute_javascript_script(script_code, None).await;
+        let mut entries: Vec<CatalogEntry> = Vec::new();
+
+        for plugin in index.plugins {
+            if let Err(err) = paths::sanitize_file_name(&plugin.folder) {
+                println!("Skipping plugin {} from source {}: {}", plugin.name, source.id(), err);
+                continue;
             }
-            "sh" => {
-                return Err(PluginError {
-                    message: format!("Plugin {}'s startup script is a shell script, which is not allowed.", plugin.name),
-                });
+
+            entries.push(CatalogEntry {
+                installed: self.installed.contains_key(&plugin.id),
+                name: plugin.name,
+                id: plugin.id,
+                version: plugin.version,
+                description: plugin.description,
+                source: source.id(),
+                folder: plugin.folder,
+                functions: plugin.functions,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /**
+     * Lists the plugins available from a single source by reading the info file of every plugin folder.
+     * This costs one request per plugin, so it is only used for sources without a registry index.
+     */
+    async fn crawl_source_catalog(&self, source: &dyn PluginSource) -> Result<Vec<CatalogEntry>, PluginError> {
+        let folder_contents = source.list_folder("plugins").await?;
+        let mut entries: Vec<CatalogEntry> = Vec::new();
+
+        for plugin_folder in folder_contents {
+            if plugin_folder.entry_type != "dir" {
+                continue;
             }
-            _ => {
-                return Err(PluginError {
-                    message: format!("Plugin {}'s startup script has an unsupported type.", plugin.name),
-                });
+
+            if let Err(err) = paths::sanitize_file_name(&plugin_folder.name) {
+                println!("Skipping plugin folder from source {}: {}", source.id(), err);
+                continue;
             }
+
+            let plugin_info = match source.fetch_file(&format!("plugins/{}/info.json", plugin_folder.name)).await {
+                Ok(info) => info,
+                Err(err) => {
+                    println!("Error getting plugin info for {}: {}", plugin_folder.name, err);
+                    continue;
+                }
+            };
+
+            let parsed_plugin_info = match manifest::parse(&plugin_info) {
+                Ok(info) => info,
+                Err(err) => {
+                    println!("Error parsing plugin info for {}: {}", plugin_folder.name, err);
+                    continue;
+                }
+            };
+
+            let installed = self.installed.contains_key(&parsed_plugin_info.id);
+
+            entries.push(CatalogEntry {
+                name: parsed_plugin_info.name,
+                id: parsed_plugin_info.id,
+                version: Some(parsed_plugin_info.version),
+                description: parsed_plugin_info.description,
+                source: source.id(),
+                folder: plugin_folder.name,
+                functions: parsed_plugin_info.functions,
+                installed,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /**
+     * Downloads a plugin from a source and stages it, to be installed with `PluginManager::install_plugin`.
+     * Nothing is written to disk unless the plugin was found in the source and verified.
+     */
+    pub async fn download_plugin(&self, source_id: &str, plugin_id: &str) -> Result<DownloadedPlugin, PluginError> {
+        if self.installed.contains_key(plugin_id) {
+            return Err(PluginError::Conflict {
+                message: format!("Plugin with id {} is already installed.", plugin_id),
+            });
+        }
+
+        let source = self.find_source(source_id)?;
+        let (plugin_folder_name, remote_plugin) = self.fetch_plugin(source, plugin_id).await?;
+
+        let local_plugin_path = self.plugins_dir.join(&plugin_folder_name);
+        if local_plugin_path.exists() {
+            return Err(PluginError::Conflict {
+                message: format!("A plugin is already installed in {}.", local_plugin_path.display()),
+            });
         }
+
+        self.stage(plugin_id, plugin_folder_name, remote_plugin).await
     }
 
-    pub async fn execute_function_script_from_id(&self, plugin_id: &str, function_path: &str, args: Option<&Vec<&str>>) -> Result<String, PluginError> {
-        let plugin = match self.plugins.iter().find(|plugin| plugin.id == plugin_id) {
-            Some(plugin) => plugin,
+    /**
+     * Downloads the newest version of an installed plugin available from the sources and stages it, to be installed
+     * with `PluginManager::update_plugin`.
+     */
+    pub async fn download_update(&self, plugin_id: &str) -> Result<DownloadedPlugin, PluginError> {
+        let installed_version = match self.installed.get(plugin_id) {
+            Some(version) => version.as_deref().and_then(parse_version),
             None => {
-                return Err(PluginError {
+                return Err(PluginError::NotFound {
                     message: format!("Plugin with id {} not found.", plugin_id),
                 });
             }
         };
 
-        let function = match plugin.function_scripts.as_ref().unwrap().iter().find(|script| script.path.as_ref().unwrap() == function_path) {
-            Some(function) => function,
+        let catalog = self.fetch_catalog().await;
+        let (entry, available_version) = match newest_catalog_entry(&catalog, plugin_id) {
+            Some(newest) => newest,
             None => {
-                return Err(PluginError {
-                    message: format!("Plugin {} has no function script at path {}.", plugin.name, function_path),
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin with id {} was not found in any source.", plugin_id),
                 });
             }
         };
 
-        self.execute_function_script(&plugin, function, args).await
+        if installed_version.is_some_and(|installed_version| installed_version >= available_version) {
+            return Err(PluginError::Conflict {
+                message: format!("Plugin with id {} is already up to date.", plugin_id),
+            });
+        }
+
+        let source = self.find_source(&entry.source)?;
+        let (plugin_folder_name, remote_plugin) = self.fetch_plugin(source, plugin_id).await?;
+
+        self.stage(plugin_id, plugin_folder_name, remote_plugin).await
     }
 
     /**
-     * Executes a function script of the plugin.
+     * Writes a downloaded plugin to a staging folder and checks that it imports.
      */
-    pub async fn execute_function_script(&self, plugin: &Plugin, plugin_script: &PluginScript, args: Option<&Vec<&str>>) -> Result<String, PluginError> {
-        let unwrapped_plugin_script_path = match plugin_script.path {
-            Some(ref path) => path,
+    async fn stage(&self, plugin_id: &str, plugin_folder_name: String, remote_plugin: RemotePlugin) -> Result<DownloadedPlugin, PluginError> {
+        let staged = install::stage(&self.plugins_dir, &plugin_folder_name, &remote_plugin)?;
+        let plugin = PluginManager::import_plugin_from_local(&path_to_string(staged.path())?).await?;
+
+        Ok(DownloadedPlugin {
+            plugin_id: plugin_id.to_string(),
+            folder_name: plugin_folder_name,
+            version: plugin.version.unwrap_or_default(),
+            info_url: remote_plugin.info_url,
+            staged,
+        })
+    }
+
+    fn find_source(&self, source_id: &str) -> Result<&dyn PluginSource, PluginError> {
+        match self.sources.iter().find(|source| source.id() == source_id) {
+            Some(source) => Ok(source.as_ref()),
+            None => Err(PluginError::NotFound {
+                message: format!("Plugin source {} not found.", source_id),
+            }),
+        }
+    }
+
+    fn trusted_keys(&self, source_id: &str) -> &[String] {
+        match self.trusted_keys.get(source_id) {
+            Some(trusted_keys) => trusted_keys,
+            None => &[],
+        }
+    }
+
+    /**
+     * Downloads every file of a plugin from a source.
+     * Returns the name of the plugin folder along with the plugin.
+     */
+    async fn fetch_plugin(&self, source: &dyn PluginSource, plugin_id: &str) -> Result<(String, RemotePlugin), PluginError> {
+        let (plugin_folder_name, remote_plugin) = match source.fetch_index().await {
+            Some(index) => {
+                let registry_plugin = match index.plugins.iter().find(|plugin| plugin.id == plugin_id) {
+                    Some(plugin) => plugin,
+                    None => {
+                        return Err(PluginError::NotFound {
+                            message: format!("Plugin with id {} not found in source {}.", plugin_id, source.id()),
+                        });
+                    }
+                };
+
+                // The folder ends up in the paths that are fetched, so it is checked before anything is.
+                paths::sanitize_file_name(&registry_plugin.folder)?;
+                let remote_plugin = self.fetch_remote_plugin_from_index(source, registry_plugin).await?;
+                (registry_plugin.folder.clone(), remote_plugin)
+            },
             None => {
-                return Err(PluginError {
-                    message: format!("Plugin {} has no such function script.", plugin.name),
-                });
+                let catalog = self.crawl_source_catalog(source).await?;
+                let entry = match catalog.iter().find(|entry| entry.id == plugin_id) {
+                    Some(entry) => entry,
+                    None => {
+                        return Err(PluginError::NotFound {
+                            message: format!("Plugin with id {} not found in source {}.", plugin_id, source.id()),
+                        });
+                    }
+                };
+
+                paths::sanitize_file_name(&entry.folder)?;
+                let remote_plugin = self.fetch_remote_plugin(source, &entry.folder).await?;
+                (entry.folder.clone(), remote_plugin)
             }
         };
 
-        let unwrapped_plugin_script_script = match plugin_script.script {
-            Some(ref script) => script,
-            None => {
-                return Err(PluginError {
-                    message: format!("Plugin {} has no function script at path {}.", plugin.name, unwrapped_plugin_script_path),
-                });
+        // Nothing downloaded gets written to disk before it has been verified.
+        let mut files: Vec<(&str, &[u8])> = vec![("info.json", remote_plugin.info.as_bytes())];
+        for (file_path, contents) in &remote_plugin.files {
+            files.push((file_path, contents.as_bytes()));
+        }
+        signature::verify_plugin(&plugin_folder_name, &files, remote_plugin.signature.as_deref(), self.trusted_keys(&source.id()))?;
+
+        // Otherwise a source could install a plugin under the id of another one.
+        let manifest = manifest::parse(&remote_plugin.info)?;
+        if manifest.id != plugin_id {
+            return Err(PluginError::SourceInvalid {
+                message: format!("Plugin {} from source {} has the id {} in its info.json.", plugin_id, source.id(), manifest.id),
+            });
+        }
+
+        Ok((plugin_folder_name, remote_plugin))
+    }
+
+    /**
+     * Downloads the files listed for a plugin in a registry index, checking each one against its hash.
+     */
+    async fn fetch_remote_plugin_from_index(&self, source: &dyn PluginSource, registry_plugin: &RegistryPlugin) -> Result<RemotePlugin, PluginError> {
+        let mut info: Option<(String, String)> = None;
+        let mut signature: Option<String> = None;
+        let mut files: Vec<(String, String)> = Vec::new();
+
+        for file in &registry_plugin.files {
+            paths::sanitize_relative_path(&file.path)?;
+            let source_path = format!("plugins/{}/{}", registry_plugin.folder, file.path);
+
+            let (file_url, contents) = match file.url {
+                Some(ref url) => (url.clone(), sources::get_text(url).await?),
+                None => (source.get_file_url(&source_path)?, source.fetch_file(&source_path).await?),
+            };
+
+            registry::verify_sha256(&source_path, contents.as_bytes(), &file.sha256)?;
+
+            if file.path == "info.json" {
+                info = Some((file_url, contents));
+            } else if file.path == signature::SIGNATURE_FILE_NAME {
+                signature = Some(contents);
+            } else {
+                files.push((file.path.clone(), contents));
             }
-        };
+        }
 
-        let script_code = unwrapped_plugin_script_script;
-        let engine = match plugin_script.engine {
-            Some(ref engine) => engine,
+        let (info_url, info) = match info {
+            Some(info) => info,
             None => {
-                return Err(PluginError {
-                    message: format!("Plugin {}'s function script at path {} does not have a type.", plugin.name, unwrapped_plugin_script_path),
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Plugin {} in the registry index does not list an info.json file.", registry_plugin.name),
                 });
             }
         };
 
-        match engine.as_str() {
-            "py" => {
-                return self.execute_python_script(script_code, args).await;
-            }
-            "js" => {
-                return self.execute_javascript_script(script_code, args).await;
+        Ok(RemotePlugin {
+            info_url,
+            info,
+            signature,
+            files,
+        })
+    }
+
+    /**
+     * Downloads the info file, the signature file and every script of a plugin folder from a source.
+     */
+    async fn fetch_remote_plugin(&self, source: &dyn PluginSource, plugin_folder_name: &str) -> Result<RemotePlugin, PluginError> {
+        let info_path = format!("plugins/{}/info.json", plugin_folder_name);
+        let info_url = source.get_file_url(&info_path)?;
+        let info = source.fetch_file(&info_path).await?;
+
+        // Unsigned plugins simply don't have a signature file, so failing to fetch it is not an error.
+        let signature = source.fetch_file(&format!("plugins/{}/{}", plugin_folder_name, signature::SIGNATURE_FILE_NAME)).await.ok();
+
+        let plugin_scripts = source.list_folder(&format!("plugins/{}/scripts", plugin_folder_name)).await?;
+        let mut files: Vec<(String, String)> = Vec::new();
+
+        for content in plugin_scripts {
+            if content.entry_type != "file" {
+                continue;
             }
-            "sh" => {
-                return Err(PluginError {
-                    message: format!("Plugin {}'s function script {} is a shell script, which is not allowed.", plugin.name, unwrapped_plugin_script_path),
-                });
+
+            paths::sanitize_file_name(&content.name)?;
+            let script = source.fetch_file(&format!("plugins/{}/scripts/{}", plugin_folder_name, content.name)).await?;
+            files.push((format!("scripts/{}", content.name), script));
+        }
+
+        Ok(RemotePlugin {
+            info_url,
+            info,
+            signature,
+            files,
+        })
+    }
+}
+
+fn path_to_string(path: &path::Path) -> Result<String, PluginError> {
+    match path.to_str() {
+        Some(path) => Ok(path.to_string()),
+        None => Err(PluginError::Io {
+            message: format!("Error reading plugin path {}.", path.display()),
+        }),
+    }
+}
+
+/**
+ * Parses a plugin version, allowing the loose versions manifests used to have, like `v1.2`.
+ */
+fn parse_version(version: &str) -> Option<semver::Version> {
+    semver::Version::parse(&manifest::normalize_version(version)).ok()
+}
+
+/**
+ * Finds the catalog entry with the highest version for a plugin id.
+ * Entries without a valid version are ignored. On ties, the first source wins.
+ */
+fn newest_catalog_entry<'a>(catalog: &'a [CatalogEntry], plugin_id: &str) -> Option<(&'a CatalogEntry, semver::Version)> {
+    let mut newest: Option<(&CatalogEntry, semver::Version)> = None;
+
+    for entry in catalog.iter().filter(|entry| entry.id == plugin_id) {
+        let version = match entry.version.as_deref().and_then(parse_version) {
+            Some(version) => version,
+            None => continue,
+        };
+
+        if newest.as_ref().is_none_or(|(_, newest_version)| version > *newest_version) {
+            newest = Some((entry, version));
+        }
+    }
+
+    newest
+}
+
+/// A plugin available from a source, as shown in the catalog.
+#[derive(Debug, Serialize, Clone)]
+pub struct CatalogEntry {
+    name: String,
+    id: String,
+    version: Option<String>,
+    description: Option<String>,
+    /// The id of the source the plugin is available from.
+    source: String,
+    /// The name of the plugin folder in the source.
+    folder: String,
+    /// The functions the plugin declares, with their schemas.
+    functions: Vec<PluginFunction>,
+    installed: bool,
+}
+
+/// A configured source along with its id, as listed to the frontend.
+#[derive(Debug, Serialize)]
+pub struct PluginSourceListing<'a> {
+    id: String,
+    #[serde(flatten)]
+    config: &'a PluginSourceConfig,
+}
+
+/// A function of an installed plugin, as listed to the frontend.
+#[derive(Debug, Serialize)]
+pub struct FunctionListing<'a> {
+    /// The full id of the function, e.g. `3f1c...:summarize`.
+    id: String,
+    plugin_id: &'a str,
+    plugin_name: &'a str,
+    function: &'a PluginFunction,
+}
+
+/// An installed plugin that has a newer version available.
+#[derive(Debug, Serialize, Clone)]
+pub struct PluginUpdate {
+    id: String,
+    name: String,
+    /// The id of the source the newer version is available from.
+    source: String,
+    installed_version: String,
+    available_version: String,
+}
+
+/// A plugin downloaded from a source, not yet written to disk.
+struct RemotePlugin {
+    info_url: String,
+    info: String,
+    /// The contents of `signature.json`, for signed plugins.
+    signature: Option<String>,
+    /// The path, relative to the plugin folder, and contents of every other file.
+    files: Vec<(String, String)>,
+}
+
+impl RemotePlugin {
+    /**
+     * Writes the plugin to the given directory.
+     */
+    fn write_to(&self, plugin_dir: &path::Path) -> Result<(), PluginError> {
+        let plugin_scripts_dir = plugin_dir.join("scripts");
+        if let Err(err) = fs::create_dir_all(&plugin_scripts_dir) {
+            return Err(PluginError::Io {
+                message: format!("Error creating plugin directory {}: {}", plugin_scripts_dir.display(), err),
+            });
+        }
+
+        let mut files: Vec<(path::PathBuf, &str)> = vec![(plugin_dir.join("info.json"), &self.info)];
+        if let Some(ref signature) = self.signature {
+            files.push((plugin_dir.join(signature::SIGNATURE_FILE_NAME), signature));
+        }
+        for (file_path, contents) in &self.files {
+            files.push((plugin_dir.join(paths::sanitize_relative_path(file_path)?), contents));
+        }
+
+        // Plugins without a startup script get an empty JavaScript one, like the local importer does.
+        if !self.files.iter().any(|(file_path, _)| file_path.starts_with("scripts/start")) {
+            files.push((plugin_scripts_dir.join("start.js"), ""));
+        }
+
+        for (file_path, contents) in files {
+            if let Some(parent) = file_path.parent() {
+                if let Err(err) = fs::create_dir_all(parent) {
+                    return Err(PluginError::Io {
+                        message: format!("Error creating directory {}: {}", parent.display(), err),
+                    });
+                }
             }
-            _ => {
-                return Err(PluginError {
-                    message: format!("Plugin {}'s function script at path {} has an unsupported type.", plugin.name, unwrapped_plugin_script_path),
+
+            if let Err(err) = fs::write(&file_path, contents) {
+                return Err(PluginError::Io {
+                    message: format!("Error writing {}: {}", file_path.display(), err),
                 });
             }
         }
+
+        Ok(())
     }
+}
+
+/// An installed plugin, as read from its manifest (see the `manifest` module) and its scripts.
+#[derive(Debug, Serialize)]
+pub struct Plugin {
+    // Information
+    /// The name of the plugin.
+    name: String,
+    /// The id of the plugin. Randomly generated UUID.
+    id: String,
+    /// The version of the plugin in semver format.
+    version: Option<String>,
+    /// A short description of what the plugin does.
+    description: Option<String>,
+
+    // Sources
+    /// The local path to the plugin.
+    local_path: Option<String>,
+    /// Link to the GitHub API, fetching the folder contents of the plugin.
+    remote_url: Option<String>,
+
+    // Scripts
+    startup_script: Option<PluginScript>,
+    function_scripts: Option<Vec<PluginScript>>,
+    /// The functions the plugin offers. See the `functions` module.
+    functions: Vec<PluginFunction>,
+    /// How long the plugin's scripts may run, in seconds, before they are killed.
+    timeout: Option<u64>,
+    /// The script in `scripts` that runs as the plugin's daemon, if the plugin has one. See the `daemon` module.
+    daemon: Option<String>,
+    /// What the plugin is allowed to do. See the `permissions` module.
+    permissions: PluginPermissions,
+    /// Overrides the default resource limits. See the `limits` module.
+    limits: ResourceLimits,
+    /// How the plugin's scripts get their arguments. See the `calling` module.
+    calling_convention: CallingConvention,
+}
 
+impl Plugin {
     /**
-     * Executes a python script from the plugin. 
+     * The timeout of a script run, preferring the one asked for by the caller over the plugin's own.
      */
-    async fn execute_python_script(&self, script: &str, args: Option<&Vec<&str>>) -> Result<String, PluginError> {
-        let output = std::process::Command::new("python3")
-            .args([["-c", script].as_slice(), args.unwrap_or(&Vec::<&str>::new()).as_slice()].concat())
-            .output()
-            .expect("failed to execute process");
+    fn timeout(&self, requested: Option<Duration>) -> Duration {
+        requested
+            .or(self.timeout.map(Duration::from_secs))
+            .unwrap_or(execution::DEFAULT_TIMEOUT)
+    }
 
-        let output = String::from_utf8_lossy(&output.stdout);
-        let output = output.to_string();
+    /**
+     * Who the plugin's scripts are to the host API.
+     */
+    fn host_caller(&self) -> HostCaller {
+        HostCaller {
+            plugin_id: self.id.clone(),
+            plugin_name: self.name.clone(),
+            local_path: self.local_path.clone(),
+            permissions: self.permissions.clone(),
+        }
+    }
 
-        Ok(output)
+    /**
+     * The host API session, sandbox and resource limits a script of the plugin runs with.
+     */
+    fn process_options(&self, runtime: &ScriptRuntime) -> ProcessOptions {
+        let caller = self.host_caller();
+
+        ProcessOptions {
+            sandbox: Some(Sandbox::new(&caller, runtime.host_api.port())),
+            session: Some(runtime.host_api.open_session(caller)),
+            cwd: self.local_path.as_ref().map(path::PathBuf::from),
+            env: self.env(&runtime.app_version),
+            limits: runtime.limits.overridden_by(&self.limits),
+            ..ProcessOptions::default()
+        }
     }
 
     /**
-     * Executes a JavaScript script from the plugin. 
-     * This implementation might change in the future to support manipulating the DOM.
+     * The environment variables that tell a script about its plugin. The plugin's data folder is created if missing.
      */
-    async fn execute_javascript_script(&self, script: &str, args: Option<&Vec<&str>>) -> Result<String, PluginError> {
-        let output = std::process::Command::new("node")
-            .args([["-e", script].as_slice(), args.unwrap_or(&Vec::<&str>::new()).as_slice()].concat())
-            .output()
-            .expect("failed to execute process");
+    fn env(&self, app_version: &str) -> Vec<(String, String)> {
+        let mut env = vec![
+            ("RAPHAEL_PLUGIN_ID".to_string(), self.id.clone()),
+            ("RAPHAEL_APP_VERSION".to_string(), app_version.to_string()),
+        ];
+
+        if let Some(ref local_path) = self.local_path {
+            let data_dir = paths::plugin_data_dir(path::Path::new(local_path));
+            if let Err(err) = fs::create_dir_all(&data_dir) {
+                println!("Error creating data folder {}: {}", data_dir.display(), err);
+            }
 
-        let output = String::from_utf8_lossy(&output.stdout);
-        let output = output.to_string();
+            env.push(("RAPHAEL_PLUGIN_DIR".to_string(), local_path.clone()));
+            env.push(("RAPHAEL_PLUGIN_DATA_DIR".to_string(), data_dir.to_string_lossy().to_string()));
+        }
 
-        Ok(output)
+        env
     }
 
     /**
-     * Executes a shell script from the plugin. 
-     * This has been temporarily disabled due to security concerns.
+     * The command line arguments and process options of a call to one of the plugin's scripts, following the
+     * plugin's calling convention. `trigger` is what the call is for, `startup` or `function`.
      */
-    async fn execute_shell_script(&self, script: &str) -> Result<String, PluginError> {
-        let output = std::process::Command::new("sh")
-            .args(["-c", script])
-            .output()
-            .expect("failed to execute process");
-
-        let output = String::from_utf8_lossy(&output.stdout);
-        let output = output.to_string();
+    fn call_options(&self, runtime: &ScriptRuntime, script_path: &str, trigger: &str, args: &[Value]) -> (Vec<String>, ProcessOptions) {
+        let mut options = self.process_options(runtime);
+
+        match self.calling_convention {
+            CallingConvention::Argv => (calling::argv(args), options),
+            CallingConvention::Json => {
+                let function = path::Path::new(script_path)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(script_path);
+
+                let envelope = InputEnvelope {
+                    function,
+                    args,
+                    context: InvocationContext {
+                        plugin_id: &self.id,
+                        plugin_version: self.version.as_deref(),
+                        trigger,
+                    },
+                    app_version: &runtime.app_version,
+                };
 
-        Ok(output)
+                options.input = Some(envelope.to_bytes());
+                options.result_envelope = true;
+                (Vec::new(), options)
+            }
+        }
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PluginSource {
-    username: Option<String>,
-    repository: Option<String>,
-    branch: Option<String>,
-}
-
-impl PluginSource {
-    pub fn new(username: Option<String>, repository: Option<String>, branch: Option<String>) -> PluginSource {
-        PluginSource {
-            username,
-            repository,
-            branch,
+    /**
+     * The path of a function script from its file name, e.g. `summarize.py`.
+     */
+    fn function_script_path(&self, function_name: &str) -> Result<String, PluginError> {
+        let function_name = paths::sanitize_relative_path(function_name)?;
+
+        match self.local_path {
+            Some(ref local_path) => path_to_string(&path::Path::new(local_path).join("scripts").join(function_name)),
+            None => Err(PluginError::NotFound {
+                message: format!("Plugin {} is not installed locally.", self.name),
+            }),
         }
     }
-    
-    pub fn get_folder_url(&self, path: &str) -> Result<String, PluginError> {
-        let username = match self.username {
-            Some(ref username) => username,
+
+    /**
+     * The startup and function scripts of the plugin that are shell scripts.
+     */
+    fn shell_scripts(&self) -> Vec<ShellScript> {
+        self.startup_script
+            .iter()
+            .chain(self.function_scripts.iter().flatten())
+            .filter(|script| script.engine.as_deref().is_some_and(is_shell_engine))
+            .filter_map(|script| {
+                let script_code = script.script.clone()?;
+
+                Some(ShellScript {
+                    path: script.path.clone().unwrap_or_default(),
+                    hash: registry::sha256_hex(script_code.as_bytes()),
+                    script: script_code,
+                })
+            })
+            .collect()
+    }
+
+    /**
+     * How to start the plugin's daemon, if it has one.
+     */
+    fn daemon_command(&self, runtime: &ScriptRuntime) -> Result<Option<DaemonCommand>, PluginError> {
+        let daemon = match self.daemon {
+            Some(ref daemon) => paths::sanitize_relative_path(daemon)?,
+            None => return Ok(None),
+        };
+
+        let local_path = match self.local_path {
+            Some(ref local_path) => local_path,
             None => {
-                return Err(PluginError {
-                    message: "Username is not set.".to_string(),
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} is not installed locally.", self.name),
                 });
             }
         };
 
-        let repository = match self.repository {
-            Some(ref repository) => repository,
+        let engine = daemon.rsplit('.').next().unwrap_or_default();
+        let program = match engine {
+            "py" => "python3",
+            "js" => "node",
+            "sh" | "bash" => {
+                return Err(PluginError::PermissionDenied {
+                    message: format!("Plugin {}'s daemon is a shell script, which is not allowed for daemons.", self.name),
+                });
+            }
+            _ => {
+                return Err(PluginError::EngineUnsupported {
+                    engine: engine.to_string(),
+                    message: format!("Plugin {}'s daemon has an unsupported type.", self.name),
+                });
+            }
+        };
+
+        let script_path = path::Path::new(local_path).join("scripts").join(daemon);
+
+        Ok(Some(DaemonCommand {
+            engine: engine.to_string(),
+            program: program.to_string(),
+            args: vec![path_to_string(&script_path)?],
+            cwd: Some(path::PathBuf::from(local_path)),
+            env: self.env(&runtime.app_version),
+            limits: runtime.limits.overridden_by(&self.limits),
+        }))
+    }
+
+    /**
+     * Starts the startup script of the plugin and returns the id of its execution.
+     * The timeout defaults to the one in the plugin's manifest, then to `execution::DEFAULT_TIMEOUT`.
+     */
+    pub fn execute_startup_script(&self, runtime: &ScriptRuntime, timeout: Option<Duration>) -> Result<String, PluginError> {
+        if self.startup_script.is_none() {
+            return Err(PluginError::NotFound {
+                message: format!("Plugin {} has no startup script.", self.name),
+            });
+        }
+
+        let script = self.startup_script.as_ref().unwrap();
+        let script_path = match (&script.path, &script.script) {
+            (Some(path), Some(_)) => path,
+            _ => {
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} has no startup script.", self.name),
+                });
+            }
+        };
+        let engine = match script.engine {
+            Some(ref engine) => engine,
             None => {
-                return Err(PluginError {
-                    message: "Repository is not set.".to_string(),
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Plugin {}'s startup script does not have a type.", self.name),
                 });
             }
         };
 
-        let branch_param = match self.branch {
-            Some(ref branch) => format!("?ref={}", branch),
-            None => "".to_string()
+        let (args, options) = self.call_options(runtime, script_path, "startup", &[]);
+
+        match engine.as_str() {
+            "py" => self.execute_python_script(runtime, script_path, &args, options, self.timeout(timeout)),
+            "js" => self.execute_javascript_script(runtime, script_path, &args, options, self.timeout(timeout)),
+            "sh" | "bash" => self.execute_shell_script(runtime, script, &args, options, self.timeout(timeout)),
+            _ => Err(PluginError::EngineUnsupported {
+                engine: engine.to_string(),
+                message: format!("Plugin {}'s startup script has an unsupported type.", self.name),
+            }),
+        }
+    }
+
+    /**
+     * Starts a function of the plugin by its id and returns the id of its execution.
+     */
+    pub fn execute_function(&self, runtime: &ScriptRuntime, function_id: &str, args: &[Value], timeout: Option<Duration>) -> Result<String, PluginError> {
+        let function = match self.functions.iter().find(|function| function.id == function_id) {
+            Some(function) => function,
+            None => {
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} has no function {}.", self.name, function_id),
+                });
+            }
         };
 
-        Ok(format!("https://api.github.com/repos/{}/{}/contents/{}{}", username, repository, path, &branch_param))
+        self.start_function(runtime, function, args, timeout)
     }
 
-    pub fn get_file_url(&self, path: &str) -> Result<String, PluginError> {
-        let username = match self.username {
-            Some(ref username) => username,
+    /**
+     * Starts the function of the plugin whose script is at a path and returns the id of its execution.
+     */
+    pub fn execute_function_script_from_path(&self, runtime: &ScriptRuntime, function_path: &str, args: &[Value], timeout: Option<Duration>) -> Result<String, PluginError> {
+        let function = self.functions
+            .iter()
+            .find(|function| self.function_script_path(&function.entry).ok().as_deref() == Some(function_path));
+
+        match function {
+            Some(function) => self.start_function(runtime, function, args, timeout),
+            None => Err(PluginError::NotFound {
+                message: format!("Plugin {} has no function script at path {}.", self.name, function_path),
+            }),
+        }
+    }
+
+    /**
+     * Starts a function of the plugin once its arguments are checked against its parameters.
+     * For plugins with a daemon, the call is sent to the daemon instead, using the id of the function as the method.
+     */
+    fn start_function(&self, runtime: &ScriptRuntime, function: &PluginFunction, args: &[Value], timeout: Option<Duration>) -> Result<String, PluginError> {
+        function.check_args(args)?;
+
+        if let Some(command) = self.daemon_command(runtime)? {
+            let call = runtime.daemons.call(self.host_caller(), command, &function.id, Value::Array(args.to_vec()));
+            return Ok(runtime.executions.start_call(&self.id, self.timeout(timeout), call));
+        }
+
+        let function_path = self.function_script_path(&function.entry)?;
+        let script = match self.function_scripts.iter().flatten().find(|script| script.path.as_deref() == Some(function_path.as_str())) {
+            Some(script) => script,
             None => {
-                return Err(PluginError {
-                    message: "Username is not set.".to_string(),
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} has no function script at path {}.", self.name, function_path),
                 });
             }
         };
 
-        let repository = match self.repository {
-            Some(ref repository) => repository,
+        self.execute_function_script(runtime, script, args, timeout)
+    }
+
+    /**
+     * Starts a function script of the plugin and returns the id of its execution.
+     */
+    pub fn execute_function_script(&self, runtime: &ScriptRuntime, plugin_script: &PluginScript, args: &[Value], timeout: Option<Duration>) -> Result<String, PluginError> {
+        let unwrapped_plugin_script_path = match plugin_script.path {
+            Some(ref path) => path,
             None => {
-                return Err(PluginError {
-                    message: "Repository is not set.".to_string(),
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} has no such function script.", self.name),
                 });
             }
         };
 
-        let branch = match self.branch {
-            Some(ref branch) => branch,
+        if plugin_script.script.is_none() {
+            return Err(PluginError::NotFound {
+                message: format!("Plugin {} has no function script at path {}.", self.name, unwrapped_plugin_script_path),
+            });
+        }
+
+        let engine = match plugin_script.engine {
+            Some(ref engine) => engine,
             None => {
-                return Err(PluginError {
-                    message: "Branch is not set.".to_string(),
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Plugin {}'s function script at path {} does not have a type.", self.name, unwrapped_plugin_script_path),
                 });
             }
         };
 
-        Ok(format!("https://raw.githubusercontent.com/{}/{}/{}/{}", username, repository, branch, path))
+        let (args, options) = self.call_options(runtime, unwrapped_plugin_script_path, "function", args);
+
+        match engine.as_str() {
+            "py" => self.execute_python_script(runtime, unwrapped_plugin_script_path, &args, options, self.timeout(timeout)),
+            "js" => self.execute_javascript_script(runtime, unwrapped_plugin_script_path, &args, options, self.timeout(timeout)),
+            "sh" | "bash" => self.execute_shell_script(runtime, plugin_script, &args, options, self.timeout(timeout)),
+            _ => Err(PluginError::EngineUnsupported {
+                engine: engine.to_string(),
+                message: format!("Plugin {}'s function script at path {} has an unsupported type.", self.name, unwrapped_plugin_script_path),
+            }),
+        }
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Plugin {
-    // Information
-    /// The name of the plugin.
-    name: String,
-    /// The id of the plugin. Randomly generated UUID.
-    id: String,
-    /// The version of the plugin in semver format.
-    version: Option<String>,
+    /**
+     * Executes a python script from the plugin. 
+     */
+    fn execute_python_script(&self, runtime: &ScriptRuntime, script_path: &str, args: &[String], options: ProcessOptions, timeout: Duration) -> Result<String, PluginError> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        runtime.executions.start(&self.id, "py", "python3", &[[script_path].as_slice(), args.as_slice()].concat(), timeout, options)
+    }
 
-    // Sources
-    /// The local path to the plugin.
-    local_path: Option<String>,
-    /// Link to the GitHub API, fetching the folder contents of the plugin.
-    remote_url: Option<String>,
+    /**
+     * Executes a JavaScript script from the plugin. 
+     * This implementation might change in the future to support manipulating the DOM.
+     */
+    fn execute_javascript_script(&self, runtime: &ScriptRuntime, script_path: &str, args: &[String], options: ProcessOptions, timeout: Duration) -> Result<String, PluginError> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        runtime.executions.start(&self.id, "js", "node", &[[script_path].as_slice(), args.as_slice()].concat(), timeout, options)
+    }
 
-    // Scripts
-    startup_script: Option<PluginScript>,
-    function_scripts: Option<Vec<PluginScript>>,
+    /**
+     * Executes a shell script from the plugin with `sh` or `bash`, depending on its engine.
+     * The plugin needs the `shell` permission, and the script has to be trusted by the user, which
+     * `PluginManager::get_runnable_plugin` checks against `script`, the contents it was imported with.
+     */
+    fn execute_shell_script(&self, runtime: &ScriptRuntime, plugin_script: &PluginScript, args: &[String], options: ProcessOptions, timeout: Duration) -> Result<String, PluginError> {
+        let (engine, script_path, script) = match (&plugin_script.engine, &plugin_script.path, &plugin_script.script) {
+            (Some(engine), Some(path), Some(script)) => (engine.as_str(), path.as_str(), script.as_str()),
+            _ => {
+                return Err(PluginError::NotFound {
+                    message: format!("Plugin {} has no such shell script.", self.name),
+                });
+            }
+        };
+
+        if !self.permissions.shell {
+            return Err(PluginError::PermissionDenied {
+                message: format!("Plugin {} does not have the permission to run shell scripts.", self.name),
+            });
+        }
+
+        // The file is what runs, so it must still be what the user trusted.
+        if fs::read_to_string(script_path).ok().as_deref() != Some(script) {
+            return Err(PluginError::Conflict {
+                message: format!("The shell script {} of plugin {} changed since it was loaded. Reload the plugins to trust it again.", script_path, self.name),
+            });
+        }
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        runtime.executions.start(&self.id, engine, engine, &[[script_path].as_slice(), args.as_slice()].concat(), timeout, options)
+    }
+}
+
+/// A shell script of a plugin, as shown to the user before trusting it.
+#[derive(Debug, Serialize)]
+pub struct ShellScript {
+    pub path: String,
+    pub script: String,
+    /// The hex-encoded SHA-256 hash of the script, which is what gets trusted.
+    pub hash: String,
+}
+
+fn is_shell_engine(engine: &str) -> bool {
+    engine == "sh" || engine == "bash"
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -797,3 +1803,12 @@ pub struct PluginScript {
     script: Option<String>,
     engine: Option<String>,
 }
+
+impl PluginScript {
+    /**
+     * The file name of the script, e.g. `summarize.py`.
+     */
+    fn file_name(&self) -> Option<&str> {
+        path::Path::new(self.path.as_ref()?).file_name()?.to_str()
+    }
+}
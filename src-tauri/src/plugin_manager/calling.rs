@@ -0,0 +1,102 @@
+//! How the arguments of a call reach a script, chosen with `calling_convention` in the plugin's manifest.
+//!
+//! With `argv`, the default, every argument is passed on the command line after the script. Strings are passed as
+//! they are, anything else as JSON.
+//!
+//! With `json`, the script gets nothing on the command line. Instead, it reads an envelope from stdin:
+//!
+//! ```json
+//! {
+//!     "function": "summarize.py",
+//!     "args": [{ "path": "~/Documents/notes.md", "max_words": 100 }],
+//!     "context": { "plugin_id": "...", "plugin_version": "1.0.0", "trigger": "function" },
+//!     "app_version": "0.1.0"
+//! }
+//! ```
+//!
+//! `trigger` is `startup` for the startup script and `function` for function scripts. The script can answer with an
+//! envelope of its own as the only thing on stdout, either `{ "result": ... }` or `{ "error": { "message": "..." } }`.
+//! The result ends up in the `output` of the execution result, and an error fails the call with
+//! `PluginError::ScriptError`. Anything else printed to stdout is kept as it is.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{ExecutionResult, PluginError};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallingConvention {
+    #[default]
+    Argv,
+    Json,
+}
+
+/// What a script written for the `json` calling convention reads from stdin.
+#[derive(Debug, Serialize)]
+pub struct InputEnvelope<'a> {
+    /// The file name of the script, e.g. `summarize.py`.
+    pub function: &'a str,
+    pub args: &'a [Value],
+    pub context: InvocationContext<'a>,
+    pub app_version: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvocationContext<'a> {
+    pub plugin_id: &'a str,
+    pub plugin_version: Option<&'a str>,
+    /// `startup` or `function`.
+    pub trigger: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResultEnvelope {
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<EnvelopeError>,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeError {
+    message: String,
+}
+
+impl InputEnvelope<'_> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/**
+ * The command line arguments of a script using the `argv` calling convention.
+ */
+pub fn argv(args: &[Value]) -> Vec<String> {
+    args.iter()
+        .map(|arg| match arg {
+            Value::String(arg) => arg.clone(),
+            arg => arg.to_string(),
+        })
+        .collect()
+}
+
+/**
+ * Reads the result envelope a script printed to stdout, if it printed one.
+ */
+pub fn read_result_envelope(mut result: ExecutionResult) -> Result<ExecutionResult, PluginError> {
+    let envelope: ResultEnvelope = match serde_json::from_str(result.stdout.trim()) {
+        Ok(envelope) => envelope,
+        Err(_) => return Ok(result),
+    };
+
+    if let Some(error) = envelope.error {
+        return Err(PluginError::ScriptError {
+            message: error.message,
+        });
+    }
+
+    result.output = envelope.result;
+    Ok(result)
+}
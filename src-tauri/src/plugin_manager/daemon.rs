@@ -0,0 +1,514 @@
+//! Daemon plugins keep one process running instead of starting a new one for every call, so they can keep models
+//! or connections warm. The manifest names the entry script, which lives in the plugin's `scripts` folder:
+//!
+//! ```json
+//! { "name": "Example", "id": "3f1c...", "daemon": "daemon.py" }
+//! ```
+//!
+//! The daemon is started the first time one of the plugin's functions is called. Calls are sent to it as JSON-RPC 2.0
+//! requests, one message per line on its stdin, with the id of the function as the method, and it answers the same
+//! way on its stdout:
+//!
+//! ```json
+//! --> { "jsonrpc": "2.0", "id": 1, "method": "summarize", "params": ["some", "args"] }
+//! <-- { "jsonrpc": "2.0", "id": 1, "result": "..." }
+//! ```
+//!
+//! Lines on stdout that are not responses are logged, as is everything on stderr. A line longer than the plugin's
+//! output limit gets the daemon killed, since it would otherwise be buffered whole. A daemon that exits is restarted,
+//! waiting longer each time it crashes soon after starting. Every run of the daemon gets its own host API token, and
+//! runs in the plugin's sandbox. When it is stopped, it first gets a `shutdown` notification and is killed if it is
+//! still running shortly after.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::ChildStdin;
+use tokio::sync::{oneshot, watch};
+
+use super::PluginError;
+use super::execution;
+use super::host_api::{HostApi, HostCaller};
+use super::execution::ProcessOptions;
+use super::limits::ResourceLimits;
+use super::sandbox::Sandbox;
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// A daemon that ran at least this long before exiting is restarted after the minimum delay again.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// How long a daemon gets to exit on its own after being asked to shut down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// The daemons of the plugins that have one, by plugin id.
+pub struct Daemons {
+    daemons: Mutex<HashMap<String, Arc<Daemon>>>,
+    host_api: Arc<HostApi>,
+}
+
+/// How to start the daemon of a plugin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaemonCommand {
+    pub engine: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+    /// The CPU time limit is ignored, since daemons keep running.
+    pub limits: ResourceLimits,
+}
+
+struct Daemon {
+    plugin_name: String,
+    command: DaemonCommand,
+    /// Who the daemon is to the host API.
+    caller: HostCaller,
+    host_api: Arc<HostApi>,
+    state: watch::Sender<DaemonState>,
+    next_request_id: AtomicU64,
+}
+
+#[derive(Clone)]
+enum DaemonState {
+    /// The daemon is starting, or waiting to be restarted.
+    Starting,
+    Running(Arc<DaemonProcess>),
+    /// The daemon could not be started and will not be retried, e.g. because its interpreter is missing.
+    Failed(PluginError),
+    Stopped,
+}
+
+/// One run of a daemon, from when it is started until it exits.
+struct DaemonProcess {
+    pid: Option<u32>,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    /// The calls waiting for a response, by request id.
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Value, PluginError>>>>,
+    exited: watch::Sender<bool>,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    id: Option<u64>,
+    result: Option<Value>,
+    error: Option<RpcErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+impl Daemons {
+    pub fn new(host_api: Arc<HostApi>) -> Daemons {
+        Daemons {
+            daemons: Mutex::new(HashMap::new()),
+            host_api,
+        }
+    }
+
+    /**
+     * Calls a method of a plugin's daemon, starting the daemon if it is not running yet.
+     * A daemon that was started with a different command or permissions, e.g. before the plugin was updated, or that
+     * failed to start is replaced.
+     * The response is returned as is if it is a string, and as JSON otherwise.
+     */
+    pub fn call(&self, caller: HostCaller, command: DaemonCommand, method: &str, params: Value) -> impl Future<Output = Result<String, PluginError>> + Send + 'static {
+        let (daemon, replaced) = {
+            let mut daemons = self.daemons.lock().unwrap();
+
+            match daemons.get(&caller.plugin_id) {
+                Some(daemon) if daemon.command == command && daemon.caller.permissions == caller.permissions && !daemon.has_failed() => (Arc::clone(daemon), None),
+                existing => {
+                    let replaced = existing.cloned();
+                    let plugin_id = caller.plugin_id.clone();
+                    let daemon = Daemon::start(caller, command, Arc::clone(&self.host_api));
+                    daemons.insert(plugin_id, Arc::clone(&daemon));
+                    (daemon, replaced)
+                }
+            }
+        };
+
+        let method = method.to_string();
+        async move {
+            if let Some(replaced) = replaced {
+                replaced.stop().await;
+            }
+
+            match daemon.call(&method, params).await? {
+                Value::String(output) => Ok(output),
+                output => Ok(output.to_string()),
+            }
+        }
+    }
+
+    /**
+     * Stops the daemon of a plugin, if it has one.
+     */
+    pub async fn stop(&self, plugin_id: &str) {
+        let daemon = self.daemons.lock().unwrap().remove(plugin_id);
+
+        if let Some(daemon) = daemon {
+            daemon.stop().await;
+        }
+    }
+
+    /**
+     * Stops every daemon. Called when the app exits.
+     */
+    pub async fn shutdown(&self) {
+        let daemons: Vec<Arc<Daemon>> = self.daemons.lock().unwrap().drain().map(|(_, daemon)| daemon).collect();
+
+        let stopping: Vec<_> = daemons
+            .into_iter()
+            .map(|daemon| tokio::spawn(async move { daemon.stop().await }))
+            .collect();
+
+        for handle in stopping {
+            let _ = handle.await;
+        }
+    }
+}
+
+impl Daemon {
+    fn start(caller: HostCaller, command: DaemonCommand, host_api: Arc<HostApi>) -> Arc<Daemon> {
+        let (state, _) = watch::channel(DaemonState::Starting);
+
+        let daemon = Arc::new(Daemon {
+            plugin_name: caller.plugin_name.clone(),
+            command,
+            caller,
+            host_api,
+            state,
+            next_request_id: AtomicU64::new(1),
+        });
+
+        tokio::spawn(Arc::clone(&daemon).supervise());
+
+        daemon
+    }
+
+    /**
+     * Runs the daemon, restarting it whenever it exits until it is stopped.
+     */
+    async fn supervise(self: Arc<Self>) {
+        let mut restart_delay = MIN_RESTART_DELAY;
+
+        loop {
+            if self.is_stopped() {
+                return;
+            }
+
+            let args: Vec<&str> = self.command.args.iter().map(String::as_str).collect();
+            let options = ProcessOptions {
+                session: Some(self.host_api.open_session(self.caller.clone())),
+                sandbox: Some(Sandbox::new(&self.caller, self.host_api.port())),
+                cwd: self.command.cwd.clone(),
+                env: self.command.env.clone(),
+                limits: ResourceLimits {
+                    cpu_time: None,
+                    ..self.command.limits.clone()
+                },
+                ..ProcessOptions::default()
+            };
+            let mut child = match execution::spawn(&self.command.engine, &self.command.program, &args, Stdio::piped(), &options) {
+                Ok(child) => child,
+                Err(err) => {
+                    println!("Error starting the daemon of plugin {}: {}", self.plugin_name, err);
+                    self.set_state(DaemonState::Failed(err));
+                    return;
+                }
+            };
+
+            let (exited, _) = watch::channel(false);
+            let process = Arc::new(DaemonProcess {
+                pid: child.id(),
+                stdin: tokio::sync::Mutex::new(child.stdin.take().unwrap()),
+                pending: Mutex::new(HashMap::new()),
+                exited,
+            });
+
+            let max_line = self.command.limits.output_bytes();
+            tokio::spawn(log_lines(child.stderr.take().unwrap(), child.id(), self.plugin_name.clone(), max_line));
+            let stdout = child.stdout.take().unwrap();
+
+            if !self.set_state(DaemonState::Running(Arc::clone(&process))) {
+                // Stopped while starting.
+                if let Some(pid) = process.pid {
+                    execution::kill_process_tree(pid);
+                }
+                return;
+            }
+
+            println!("Started the daemon of plugin {}.", self.plugin_name);
+            let started_at = Instant::now();
+
+            let (status, ()) = tokio::join!(
+                async {
+                    let status = child.wait().await;
+                    // Take down whatever the daemon left running, which would otherwise keep its stdout open.
+                    if let Some(pid) = process.pid {
+                        execution::kill_process_tree(pid);
+                    }
+                    status
+                },
+                read_responses(stdout, &process, &self.plugin_name, max_line),
+            );
+
+            process.exited.send_replace(true);
+            drop(options);
+            process.fail_pending(&format!("The daemon of plugin {} exited before answering.", self.plugin_name));
+
+            if !self.set_state(DaemonState::Starting) {
+                return;
+            }
+
+            if started_at.elapsed() >= STABLE_AFTER {
+                restart_delay = MIN_RESTART_DELAY;
+            }
+
+            match status {
+                Ok(status) => println!("The daemon of plugin {} exited with {}, restarting in {} seconds.", self.plugin_name, status, restart_delay.as_secs()),
+                Err(err) => println!("Error waiting for the daemon of plugin {}, restarting in {} seconds: {}", self.plugin_name, restart_delay.as_secs(), err),
+            }
+
+            tokio::time::sleep(restart_delay).await;
+            restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+        }
+    }
+
+    /**
+     * Sends a request to the daemon and waits for its response, waiting for the daemon to start first if needed.
+     */
+    async fn call(&self, method: &str, params: Value) -> Result<Value, PluginError> {
+        let state = {
+            let mut state = self.state.subscribe();
+            let current = match state.wait_for(|state| !matches!(state, DaemonState::Starting)).await {
+                Ok(state) => state.clone(),
+                Err(_) => DaemonState::Stopped,
+            };
+            current
+        };
+
+        let process = match state {
+            DaemonState::Running(process) => process,
+            DaemonState::Failed(err) => return Err(err),
+            DaemonState::Starting | DaemonState::Stopped => {
+                return Err(PluginError::DaemonUnavailable {
+                    message: format!("The daemon of plugin {} is stopped.", self.plugin_name),
+                });
+            }
+        };
+
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        process.pending.lock().unwrap().insert(request_id, sender);
+
+        // Forget the request if the call is dropped, e.g. because it timed out.
+        let _pending = PendingRequest {
+            process: &process,
+            request_id,
+        };
+
+        process.send(&json!({
+            "jsonrpc": "2.0",
+            "id": request_id,
+            "method": method,
+            "params": params,
+        })).await?;
+
+        match receiver.await {
+            Ok(result) => result,
+            Err(_) => Err(PluginError::DaemonUnavailable {
+                message: format!("The daemon of plugin {} exited before answering.", self.plugin_name),
+            }),
+        }
+    }
+
+    /**
+     * Asks the daemon to shut down, killing it if it does not exit in time, and keeps it from being restarted.
+     */
+    async fn stop(&self) {
+        let process = match self.state.send_replace(DaemonState::Stopped) {
+            DaemonState::Running(process) => process,
+            _ => return,
+        };
+
+        println!("Stopping the daemon of plugin {}.", self.plugin_name);
+
+        let mut exited = process.exited.subscribe();
+        let exited_in_time = process.send(&json!({ "jsonrpc": "2.0", "method": "shutdown" })).await.is_ok()
+            && tokio::time::timeout(SHUTDOWN_GRACE, exited.wait_for(|exited| *exited)).await.is_ok();
+
+        if !exited_in_time {
+            if let Some(pid) = process.pid {
+                execution::kill_process_tree(pid);
+            }
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        matches!(*self.state.borrow(), DaemonState::Stopped)
+    }
+
+    fn has_failed(&self) -> bool {
+        matches!(*self.state.borrow(), DaemonState::Failed(_))
+    }
+
+    /**
+     * Changes the state of the daemon unless it was stopped. Returns whether it was changed.
+     */
+    fn set_state(&self, new_state: DaemonState) -> bool {
+        let mut new_state = Some(new_state);
+
+        self.state.send_if_modified(|state| {
+            if matches!(state, DaemonState::Stopped) {
+                return false;
+            }
+
+            *state = new_state.take().unwrap();
+            true
+        })
+    }
+}
+
+impl DaemonProcess {
+    /**
+     * Writes a message to the daemon's stdin, as a single line.
+     */
+    async fn send(&self, message: &Value) -> Result<(), PluginError> {
+        let mut line = serde_json::to_vec(message).unwrap();
+        line.push(b'\n');
+
+        let mut stdin = self.stdin.lock().await;
+        if let Err(err) = stdin.write_all(&line).await {
+            return Err(PluginError::DaemonUnavailable {
+                message: format!("Error writing to the daemon: {}", err),
+            });
+        }
+        if let Err(err) = stdin.flush().await {
+            return Err(PluginError::DaemonUnavailable {
+                message: format!("Error writing to the daemon: {}", err),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn fail_pending(&self, message: &str) {
+        for (_, sender) in self.pending.lock().unwrap().drain() {
+            let _ = sender.send(Err(PluginError::DaemonUnavailable {
+                message: message.to_string(),
+            }));
+        }
+    }
+}
+
+/// Removes a request from the pending ones when dropped.
+struct PendingRequest<'a> {
+    process: &'a DaemonProcess,
+    request_id: u64,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.process.pending.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+/**
+ * Hands the responses on the daemon's stdout to the calls waiting for them, until the daemon closes it.
+ */
+async fn read_responses<R: AsyncRead + Unpin>(stdout: R, process: &DaemonProcess, plugin_name: &str, max_line: usize) {
+    let mut stdout = BufReader::new(stdout);
+
+    loop {
+        let line = match next_line(&mut stdout, max_line).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(err) => {
+                println!("Error reading the output of the daemon of plugin {}, stopping it: {}", plugin_name, err);
+                if let Some(pid) = process.pid {
+                    execution::kill_process_tree(pid);
+                }
+                return;
+            }
+        };
+
+        let response = match serde_json::from_str::<RpcResponse>(&line) {
+            Ok(RpcResponse { id: Some(id), result, error }) => (id, result, error),
+            _ => {
+                println!("[{}] {}", plugin_name, line);
+                continue;
+            }
+        };
+
+        let (request_id, result, error) = response;
+        let sender = match process.pending.lock().unwrap().remove(&request_id) {
+            Some(sender) => sender,
+            // The call already gave up on this request.
+            None => continue,
+        };
+
+        let result = match error {
+            Some(error) => Err(PluginError::RpcError {
+                code: error.code,
+                message: error.message,
+            }),
+            None => Ok(result.unwrap_or(Value::Null)),
+        };
+
+        let _ = sender.send(result);
+    }
+}
+
+async fn log_lines<R: AsyncRead + Unpin>(stream: R, pid: Option<u32>, plugin_name: String, max_line: usize) {
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        match next_line(&mut stream, max_line).await {
+            Ok(Some(line)) => println!("[{}] {}", plugin_name, line),
+            Ok(None) => return,
+            Err(err) => {
+                println!("Error reading the output of the daemon of plugin {}, stopping it: {}", plugin_name, err);
+                if let Some(pid) = pid {
+                    execution::kill_process_tree(pid);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Reads the next line without its line ending. Lines longer than `max_line` bytes are an error rather than being
+ * buffered whole.
+ */
+pub async fn next_line<R: AsyncBufRead + Unpin>(reader: &mut R, max_line: usize) -> io::Result<Option<String>> {
+    let mut line: Vec<u8> = Vec::new();
+    let limit = (max_line as u64).saturating_add(1);
+
+    if reader.take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > max_line {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("a line is longer than {} bytes", max_line)));
+    }
+
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
@@ -0,0 +1,96 @@
+//! Errors returned by the plugin manager.
+//! They are sent to the frontend as JSON tagged with `kind`, e.g.
+//! `{ "kind": "ScriptFailed", "exit_code": 1, "stderr": "..." }`, so the UI can react to each kind differently.
+
+use std::{error, fmt};
+use serde::Serialize;
+
+use super::PluginPermissions;
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind")]
+pub enum PluginError {
+    /// A plugin, source or script does not exist.
+    NotFound { message: String },
+    /// The request conflicts with the current state, e.g. installing a plugin that is already installed.
+    Conflict { message: String },
+    /// A plugin's `info.json` is missing or malformed.
+    ManifestInvalid { message: String },
+    /// A source is misconfigured or serves data in an unexpected shape.
+    SourceInvalid { message: String },
+    /// A request to a source failed.
+    Network { message: String },
+    /// Reading or writing the filesystem failed.
+    Io { message: String },
+    /// A downloaded file does not match its checksum or signature.
+    VerificationFailed { message: String },
+    /// A name provided by a source would resolve outside of the plugins directory.
+    UnsafePath { message: String },
+    /// The script engine is not one Raphael knows how to run.
+    EngineUnsupported { engine: String, message: String },
+    /// The interpreter for the script engine could not be started, usually because it is not installed.
+    EngineMissing { engine: String },
+    /// The script ran but exited unsuccessfully. `exit_code` is unset if it was killed by a signal.
+    ScriptFailed { exit_code: Option<i32>, stderr: String },
+    /// The script answered with an error in its result envelope. See the `calling` module.
+    ScriptError { message: String },
+    /// The script ran longer than it was allowed to and was killed. `timeout` is in seconds.
+    TimedOut { timeout: u64 },
+    /// The script ran out of one of its resource limits: `memory`, `cpu_time`, `open_files` or `processes`.
+    LimitExceeded { limit: String, message: String },
+    /// The script was killed at the user's request.
+    Cancelled,
+    /// A plugin daemon answered a call with a JSON-RPC error.
+    RpcError { code: i64, message: String },
+    /// A plugin daemon is not running, or exited before answering a call.
+    DaemonUnavailable { message: String },
+    /// The plugin is not allowed to do what it asked for.
+    PermissionDenied { message: String },
+    /// The plugin declares permissions the user has not approved yet, so it cannot run.
+    PermissionsNotGranted { plugin_id: String, missing: Box<PluginPermissions> },
+    /// The plugin has shell scripts the user has not seen and trusted yet, by path.
+    ShellScriptsNotTrusted { plugin_id: String, paths: Vec<String> },
+    /// The arguments of a request are invalid.
+    InvalidInput { message: String },
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::NotFound { message }
+            | PluginError::Conflict { message }
+            | PluginError::ManifestInvalid { message }
+            | PluginError::SourceInvalid { message }
+            | PluginError::Network { message }
+            | PluginError::Io { message }
+            | PluginError::VerificationFailed { message }
+            | PluginError::UnsafePath { message }
+            | PluginError::EngineUnsupported { message, .. }
+            | PluginError::RpcError { message, .. }
+            | PluginError::LimitExceeded { message, .. }
+            | PluginError::ScriptError { message }
+            | PluginError::DaemonUnavailable { message }
+            | PluginError::PermissionDenied { message }
+            | PluginError::InvalidInput { message } => write!(f, "{}", message),
+            PluginError::EngineMissing { engine } => {
+                write!(f, "The interpreter for {} scripts could not be started. Is it installed?", engine)
+            }
+            PluginError::ScriptFailed { exit_code: Some(exit_code), stderr } => {
+                write!(f, "Script exited with code {}: {}", exit_code, stderr.trim())
+            }
+            PluginError::ScriptFailed { exit_code: None, stderr } => {
+                write!(f, "Script was terminated by a signal: {}", stderr.trim())
+            }
+            PluginError::TimedOut { timeout } => write!(f, "Script did not finish within {} seconds and was killed.", timeout),
+            PluginError::Cancelled => write!(f, "Script was cancelled."),
+            PluginError::PermissionsNotGranted { plugin_id, .. } => {
+                write!(f, "Plugin {} cannot run until its permissions are approved.", plugin_id)
+            }
+            PluginError::ShellScriptsNotTrusted { plugin_id, paths } => {
+                write!(f, "Plugin {} cannot run until its shell scripts are trusted: {}", plugin_id, paths.join(", "))
+            }
+        }
+    }
+}
+
+impl error::Error for PluginError {}
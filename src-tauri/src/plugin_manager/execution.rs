@@ -0,0 +1,591 @@
+//! Runs plugin scripts as child processes and collects what they output.
+//!
+//! Scripts run in the background: starting one returns an execution id right away, which can then be used to wait
+//! for the result or to cancel the script. Every script is killed, along with any process it started, once it runs
+//! past its timeout.
+//!
+//! While a script runs, each line it prints is reported to the execution listener as it comes, followed by the
+//! result once it exits. The app forwards these to the frontend as events.
+//!
+//! A finished script is forgotten once its result is waited for, or `RESULT_RETENTION` after it exits if nobody does.
+//!
+//! Scripts don't inherit the app's environment, only the variables in `ENV_ALLOWLIST`, plus the ones they are
+//! started with.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+
+use super::PluginError;
+use super::calling;
+use super::host_api::HostSession;
+use super::limits::{self, ResourceLimits};
+use super::sandbox::{self, Sandbox};
+
+/// How much of each output stream is kept by default. Anything past it is read and discarded so the script never blocks on a full pipe.
+pub const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+
+/// How long a script may run when neither the caller nor the plugin's manifest says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the result of a script is kept for someone to wait for it after the script exits.
+const RESULT_RETENTION: Duration = Duration::from_secs(60);
+
+/// Lines longer than this are reported in several parts.
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// The variables of the app's environment that scripts get. The rest may hold secrets the app was started with.
+const ENV_ALLOWLIST: [&str; 25] = [
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "LANG", "LANGUAGE", "LC_ALL", "LC_CTYPE", "LC_MESSAGES", "TZ", "TMPDIR",
+    "TERM",
+    // Windows
+    "SYSTEMROOT", "SYSTEMDRIVE", "WINDIR", "COMSPEC", "PATHEXT", "TEMP", "TMP", "USERPROFILE", "APPDATA",
+    "LOCALAPPDATA", "PROGRAMDATA", "NUMBER_OF_PROCESSORS",
+];
+
+/// The outcome of a script that exited successfully.
+#[derive(Debug, Serialize, Clone)]
+pub struct ExecutionResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    /// How long the script ran, in milliseconds.
+    pub duration: u64,
+    /// Whether stdout or stderr was cut off at the output limit.
+    pub truncated: bool,
+    /// The result from the script's result envelope, if it printed one. See the `calling` module.
+    pub output: Option<serde_json::Value>,
+}
+
+/// Something that happened while a script was running.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ExecutionEvent {
+    /// A line printed by the script, without its line ending.
+    Output {
+        plugin_id: String,
+        execution_id: String,
+        stream: OutputStream,
+        line: String,
+    },
+    /// The script is done. Exactly one of `result` and `error` is set.
+    Finished {
+        plugin_id: String,
+        execution_id: String,
+        result: Option<ExecutionResult>,
+        error: Option<PluginError>,
+    },
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl ExecutionEvent {
+    /**
+     * The name of the event the frontend listens to.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExecutionEvent::Output { .. } => "plugin-output",
+            ExecutionEvent::Finished { .. } => "plugin-execution-finished",
+        }
+    }
+}
+
+/// Called with every event of every execution.
+pub type ExecutionListener = Arc<dyn Fn(ExecutionEvent) + Send + Sync>;
+
+/// How a plugin process is set up, besides its command line.
+#[derive(Default)]
+pub struct ProcessOptions {
+    /// Lets the process use the host API until it exits.
+    pub session: Option<HostSession>,
+    /// The working directory of the process. The app's own if unset.
+    pub cwd: Option<PathBuf>,
+    /// Set on top of the allowed variables of the app's environment.
+    pub env: Vec<(String, String)>,
+    pub sandbox: Option<Sandbox>,
+    pub limits: ResourceLimits,
+    /// Written to the process's stdin, which is then closed. Stdin is empty if unset.
+    pub input: Option<Vec<u8>>,
+    /// Whether stdout may hold a result envelope. See the `calling` module.
+    pub result_envelope: bool,
+}
+
+/// The scripts that are running, or that finished but whose result was not collected yet.
+pub struct Executions {
+    next_id: AtomicU64,
+    executions: Arc<Mutex<HashMap<String, Execution>>>,
+    listener: Option<ExecutionListener>,
+}
+
+struct Execution {
+    pid: Option<u32>,
+    /// Kills the script when sent to. Taken once used.
+    cancel: Option<oneshot::Sender<()>>,
+    /// Receives the result once the script is done. Taken by whoever waits for it.
+    result: Option<oneshot::Receiver<Result<ExecutionResult, PluginError>>>,
+}
+
+impl Executions {
+    /**
+     * `listener` is notified of the output and result of every script.
+     */
+    pub fn new(listener: Option<ExecutionListener>) -> Executions {
+        Executions {
+            next_id: AtomicU64::new(1),
+            executions: Arc::new(Mutex::new(HashMap::new())),
+            listener,
+        }
+    }
+
+    /**
+     * Starts a program for a plugin in the background and returns the id of its execution.
+     * `engine` is only used to report which interpreter is missing when the program cannot be started.
+     */
+    pub fn start(&self, plugin_id: &str, engine: &str, program: &str, args: &[&str], timeout: Duration, mut options: ProcessOptions) -> Result<String, PluginError> {
+        let stdin = if options.input.is_some() { Stdio::piped() } else { Stdio::null() };
+        let mut child = spawn(engine, program, args, stdin, &options)?;
+
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), options.input.take()) {
+            // Written alongside reading the output, so a script that prints before reading everything doesn't block.
+            // A script that exits without reading its input is not an error.
+            tokio::spawn(async move {
+                let _ = stdin.write_all(&input).await;
+            });
+        }
+
+        let (execution_id, cancel_receiver, result_sender) = self.register(child.id());
+        let reporter = self.reporter(plugin_id, &execution_id);
+        let forget = self.forget_later(&execution_id);
+        let program = program.to_string();
+        tokio::spawn(async move {
+            let mut result = collect(child, &program, timeout, cancel_receiver, &reporter, options.limits.output_bytes()).await;
+            result = result.map_err(|err| limits::explain_failure(err, &options.limits));
+            if options.result_envelope {
+                result = result.and_then(calling::read_result_envelope);
+            }
+            if options.sandbox.is_some() {
+                result = result.map_err(sandbox::explain_failure);
+            }
+            // The script is gone, so its host API token stops working.
+            drop(options);
+            reporter.finished(&result);
+            // The receiver is gone if nobody is going to wait for the result anymore.
+            let _ = result_sender.send(result);
+            forget.await;
+        });
+
+        Ok(execution_id)
+    }
+
+    /**
+     * Tracks a call that is not a process of its own, like a request to a plugin daemon, as an execution.
+     * Whatever the call returns becomes the stdout of the result. Timing out or cancelling the execution drops the call.
+     */
+    pub fn start_call<F>(&self, plugin_id: &str, timeout: Duration, call: F) -> String
+    where
+        F: Future<Output = Result<String, PluginError>> + Send + 'static,
+    {
+        let (execution_id, cancel_receiver, result_sender) = self.register(None);
+        let reporter = self.reporter(plugin_id, &execution_id);
+        let forget = self.forget_later(&execution_id);
+        tokio::spawn(async move {
+            let started_at = Instant::now();
+
+            let result = tokio::select! {
+                output = call => output.map(|stdout| ExecutionResult {
+                    stdout,
+                    stderr: String::new(),
+                    exit_code: None,
+                    duration: started_at.elapsed().as_millis() as u64,
+                    truncated: false,
+                    output: None,
+                }),
+                _ = tokio::time::sleep(timeout) => Err(PluginError::TimedOut {
+                    timeout: timeout.as_secs(),
+                }),
+                Ok(()) = cancel_receiver => Err(PluginError::Cancelled),
+            };
+
+            reporter.finished(&result);
+            let _ = result_sender.send(result);
+            forget.await;
+        });
+
+        execution_id
+    }
+
+    /**
+     * Adds a new execution to the registry.
+     */
+    fn register(&self, pid: Option<u32>) -> (String, oneshot::Receiver<()>, oneshot::Sender<Result<ExecutionResult, PluginError>>) {
+        let execution_id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (cancel_sender, cancel_receiver) = oneshot::channel();
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.executions.lock().unwrap().insert(execution_id.clone(), Execution {
+            pid,
+            cancel: Some(cancel_sender),
+            result: Some(result_receiver),
+        });
+
+        (execution_id, cancel_receiver, result_sender)
+    }
+
+    /**
+     * Removes an execution from the registry `RESULT_RETENTION` after being awaited, in case nobody waits for its result.
+     */
+    fn forget_later(&self, execution_id: &str) -> impl Future<Output = ()> {
+        let executions = Arc::clone(&self.executions);
+        let execution_id = execution_id.to_string();
+
+        async move {
+            tokio::time::sleep(RESULT_RETENTION).await;
+            executions.lock().unwrap().remove(&execution_id);
+        }
+    }
+
+    fn reporter(&self, plugin_id: &str, execution_id: &str) -> Reporter {
+        Reporter {
+            plugin_id: plugin_id.to_string(),
+            execution_id: execution_id.to_string(),
+            listener: self.listener.clone(),
+        }
+    }
+
+    /**
+     * Waits for a script to finish and returns its result. The execution is forgotten afterwards.
+     */
+    pub async fn wait(&self, execution_id: &str) -> Result<ExecutionResult, PluginError> {
+        let receiver = match self.executions.lock().unwrap().get_mut(execution_id) {
+            Some(execution) => match execution.result.take() {
+                Some(receiver) => receiver,
+                None => {
+                    return Err(PluginError::Conflict {
+                        message: format!("Execution {} is already being waited for.", execution_id),
+                    });
+                }
+            },
+            None => {
+                return Err(PluginError::NotFound {
+                    message: format!("Execution {} not found.", execution_id),
+                });
+            }
+        };
+
+        let result = receiver.await;
+        self.executions.lock().unwrap().remove(execution_id);
+
+        match result {
+            Ok(result) => result,
+            Err(_) => Err(PluginError::Io {
+                message: format!("Execution {} stopped without a result.", execution_id),
+            }),
+        }
+    }
+
+    /**
+     * Kills a running script and everything it started. Cancelling a script that already finished does nothing.
+     */
+    pub fn cancel(&self, execution_id: &str) -> Result<(), PluginError> {
+        let mut executions = self.executions.lock().unwrap();
+
+        let execution = match executions.get_mut(execution_id) {
+            Some(execution) => execution,
+            None => {
+                return Err(PluginError::NotFound {
+                    message: format!("Execution {} not found.", execution_id),
+                });
+            }
+        };
+
+        if let Some(cancel) = execution.cancel.take() {
+            // Sending fails if the script already finished, which is fine.
+            let _ = cancel.send(());
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Kills every running script. Called when the app exits, so it does not rely on the async runtime still running.
+     */
+    pub fn shutdown(&self) {
+        let mut executions = self.executions.lock().unwrap();
+
+        for (execution_id, execution) in executions.iter_mut() {
+            let cancel = match execution.cancel.take() {
+                Some(cancel) => cancel,
+                None => continue,
+            };
+
+            // The receiver is dropped once the script has exited and been reaped, after which its pid may be reused.
+            if cancel.is_closed() {
+                continue;
+            }
+
+            println!("Stopping execution {}.", execution_id);
+            if let Some(pid) = execution.pid {
+                kill_process_tree(pid);
+            }
+            let _ = cancel.send(());
+        }
+    }
+}
+
+/**
+ * Starts a program with its output piped, in a process group of its own so it can be killed along with everything it starts.
+ * `engine` is only used to report which interpreter is missing when the program cannot be started.
+ */
+pub fn spawn(engine: &str, program: &str, args: &[&str], stdin: Stdio, options: &ProcessOptions) -> Result<Child, PluginError> {
+    let inherited_env = ENV_ALLOWLIST.iter().filter_map(|name| Some((*name, std::env::var_os(name)?)));
+    let session_env = options.session.as_ref().map(HostSession::env).unwrap_or_default();
+
+    // tokio only has `process_group` under `tokio_unstable`, so it is set on the std command it wraps.
+    let mut std_command = std::process::Command::new(program);
+    std_command
+        .args(args)
+        .env_clear()
+        .envs(inherited_env)
+        .envs(options.env.iter().cloned())
+        .envs(session_env)
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if let Some(ref cwd) = options.cwd {
+        std_command.current_dir(cwd);
+    }
+
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut std_command, 0);
+
+    let mut command = Command::from(std_command);
+    command.kill_on_drop(true);
+
+    options.limits.apply(&mut command);
+    if let Some(ref sandbox) = options.sandbox {
+        sandbox.apply(&mut command, program)?;
+    }
+
+    match command.spawn() {
+        Ok(child) => Ok(child),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(PluginError::EngineMissing {
+            engine: engine.to_string(),
+        }),
+        Err(err) => Err(PluginError::Io {
+            message: format!("Error starting {}: {}", program, err),
+        }),
+    }
+}
+
+/// Why `collect` stopped waiting for a script.
+enum Outcome<T> {
+    Exited(io::Result<T>),
+    TimedOut,
+    Cancelled,
+}
+
+/**
+ * Waits for a script to exit while reading its output, keeping at most `max_output` bytes of each stream, and killing it if it times out or is cancelled.
+ * A non-zero exit is returned as `PluginError::ScriptFailed`.
+ */
+async fn collect(mut child: Child, program: &str, timeout: Duration, cancel: oneshot::Receiver<()>, reporter: &Reporter, max_output: usize) -> Result<ExecutionResult, PluginError> {
+    let started_at = Instant::now();
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let output = async {
+        let (stdout, stderr) = tokio::try_join!(
+            read_output(stdout, OutputStream::Stdout, reporter, max_output),
+            read_output(stderr, OutputStream::Stderr, reporter, max_output),
+        )?;
+        let status = child.wait().await?;
+        Ok::<_, io::Error>((stdout, stderr, status))
+    };
+
+    let outcome = tokio::select! {
+        output = output => Outcome::Exited(output),
+        _ = tokio::time::sleep(timeout) => Outcome::TimedOut,
+        // Only completes when a cancellation is sent, not when the sender is dropped.
+        Ok(()) = cancel => Outcome::Cancelled,
+    };
+
+    let (stdout, stderr, status) = match outcome {
+        Outcome::Exited(Ok(output)) => output,
+        Outcome::Exited(Err(err)) => {
+            kill_tree(&mut child);
+            return Err(PluginError::Io {
+                message: format!("Error reading the output of {}: {}", program, err),
+            });
+        }
+        Outcome::TimedOut => {
+            kill_tree(&mut child);
+            return Err(PluginError::TimedOut {
+                timeout: timeout.as_secs(),
+            });
+        }
+        Outcome::Cancelled => {
+            kill_tree(&mut child);
+            return Err(PluginError::Cancelled);
+        }
+    };
+
+    let result = ExecutionResult {
+        stdout: String::from_utf8_lossy(&stdout.0).to_string(),
+        stderr: String::from_utf8_lossy(&stderr.0).to_string(),
+        exit_code: status.code(),
+        duration: started_at.elapsed().as_millis() as u64,
+        truncated: stdout.1 || stderr.1,
+        output: None,
+    };
+
+    // Sent by the kernel once the script used up its CPU time.
+    #[cfg(unix)]
+    if std::os::unix::process::ExitStatusExt::signal(&status) == Some(libc::SIGXCPU) {
+        return Err(PluginError::LimitExceeded {
+            limit: "cpu_time".to_string(),
+            message: "The script ran out of CPU time.".to_string(),
+        });
+    }
+
+    if !status.success() {
+        return Err(PluginError::ScriptFailed {
+            exit_code: result.exit_code,
+            stderr: result.stderr,
+        });
+    }
+
+    Ok(result)
+}
+
+/**
+ * Kills a script along with every process it started.
+ */
+fn kill_tree(child: &mut Child) {
+    let pid = match child.id() {
+        Some(pid) => pid,
+        // The script already exited and was reaped.
+        None => return,
+    };
+
+    kill_process_tree(pid);
+
+    if let Err(err) = child.start_kill() {
+        println!("Error killing process {}: {}", pid, err);
+    }
+}
+
+/**
+ * Kills the process tree of a program that was started by `spawn`.
+ */
+pub fn kill_process_tree(pid: u32) {
+    #[cfg(unix)]
+    {
+        // The script leads its own process group, whose id is its pid.
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        if let Err(err) = std::process::Command::new("taskkill")
+            .args(["/PID", &pid.to_string(), "/T", "/F"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+        {
+            println!("Error killing process tree {}: {}", pid, err);
+        }
+    }
+}
+
+/// Reports the events of one execution to the listener, if there is one.
+struct Reporter {
+    plugin_id: String,
+    execution_id: String,
+    listener: Option<ExecutionListener>,
+}
+
+impl Reporter {
+    fn output(&self, stream: OutputStream, line: &[u8]) {
+        if let Some(listener) = &self.listener {
+            listener(ExecutionEvent::Output {
+                plugin_id: self.plugin_id.clone(),
+                execution_id: self.execution_id.clone(),
+                stream,
+                line: String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).to_string(),
+            });
+        }
+    }
+
+    fn finished(&self, result: &Result<ExecutionResult, PluginError>) {
+        if let Some(listener) = &self.listener {
+            listener(ExecutionEvent::Finished {
+                plugin_id: self.plugin_id.clone(),
+                execution_id: self.execution_id.clone(),
+                result: result.as_ref().ok().cloned(),
+                error: result.as_ref().err().cloned(),
+            });
+        }
+    }
+}
+
+/**
+ * Reads a stream to its end, reporting it line by line and keeping at most `max_output` bytes of it.
+ * Returns what was kept and whether anything was discarded.
+ */
+async fn read_output<R: AsyncRead + Unpin>(mut reader: R, stream: OutputStream, reporter: &Reporter, max_output: usize) -> io::Result<(Vec<u8>, bool)> {
+    let mut output = Vec::new();
+    let mut truncated = false;
+    let mut line = Vec::new();
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+
+        let kept = read.min(max_output - output.len());
+        output.extend_from_slice(&buffer[..kept]);
+        if kept < read {
+            truncated = true;
+        }
+
+        for &byte in &buffer[..read] {
+            if byte == b'\n' {
+                reporter.output(stream, &line);
+                line.clear();
+            } else {
+                line.push(byte);
+                if line.len() >= MAX_LINE_BYTES {
+                    reporter.output(stream, &line);
+                    line.clear();
+                }
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        reporter.output(stream, &line);
+    }
+
+    Ok((output, truncated))
+}
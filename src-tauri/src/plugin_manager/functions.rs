@@ -0,0 +1,206 @@
+//! Plugins declare the functions they offer in the `functions` section of their manifest:
+//!
+//! ```json
+//! {
+//!     "functions": [
+//!         {
+//!             "id": "summarize",
+//!             "entry": "summarize.py",
+//!             "description": "Summarizes a text file.",
+//!             "parameters": {
+//!                 "type": "array",
+//!                 "items": [{ "type": "string" }, { "type": "integer", "minimum": 1 }],
+//!                 "minItems": 1
+//!             },
+//!             "result": { "type": "string" }
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! `entry` is the script in the plugin's `scripts` folder that runs the function. `parameters` is a JSON Schema that
+//! the list of arguments of every call is checked against before the script is started, and `result` describes what
+//! the script returns in its result envelope (see the `calling` module). Both are optional and are shown to the UI.
+//! Both are compiled when the manifest is read, so a plugin with an invalid schema is refused when it is installed.
+//!
+//! Plugins that declare no functions have one for each script in `scripts` that is not the startup script, named after
+//! its file, e.g. `summarize.py`. When functions are declared, only their entries can be called.
+//!
+//! Outside of their plugin, functions are identified by the id of the plugin and their own, e.g. `3f1c...:summarize`,
+//! which stays the same wherever the plugin is installed.
+
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use jsonschema::JSONSchema;
+
+use super::{paths, PluginError};
+
+/// Separates the id of the plugin from the id of the function in the full id of a function.
+pub const ID_SEPARATOR: char = ':';
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PluginFunction {
+    /// How the function is called, unique within the plugin.
+    pub id: String,
+    /// The file name of the script in the plugin's `scripts` folder, e.g. `summarize.py`.
+    pub entry: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The JSON Schema of the list of arguments.
+    #[serde(default)]
+    pub parameters: Option<Value>,
+    /// `parameters`, compiled by `validate`.
+    #[serde(skip)]
+    parameters_schema: Option<Arc<JSONSchema>>,
+    /// The JSON Schema of the result.
+    #[serde(default)]
+    pub result: Option<Value>,
+}
+
+impl PluginFunction {
+    /**
+     * The function of a plugin that declares none, for one of its scripts.
+     */
+    pub fn from_entry(entry: &str) -> PluginFunction {
+        PluginFunction {
+            id: entry.to_string(),
+            entry: entry.to_string(),
+            description: None,
+            parameters: None,
+            parameters_schema: None,
+            result: None,
+        }
+    }
+
+    /**
+     * Checks that the arguments of a call match the parameters of the function.
+     */
+    pub fn check_args(&self, args: &[Value]) -> Result<(), PluginError> {
+        let schema = match self.parameters_schema {
+            Some(ref schema) => schema,
+            None => return Ok(()),
+        };
+
+        let args = Value::Array(args.to_vec());
+
+        if let Err(errors) = schema.validate(&args) {
+            let errors: Vec<String> = errors
+                .map(|error| {
+                    let path = error.instance_path.to_string();
+                    if path.is_empty() { error.to_string() } else { format!("{}: {}", path, error) }
+                })
+                .collect();
+
+            return Err(PluginError::InvalidInput {
+                message: format!("Invalid arguments for function {}: {}", self.id, errors.join("; ")),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * The full id of a function of a plugin, e.g. `3f1c...:summarize`.
+ */
+pub fn qualified_id(plugin_id: &str, function_id: &str) -> String {
+    format!("{}{}{}", plugin_id, ID_SEPARATOR, function_id)
+}
+
+/**
+ * Splits the full id of a function into the id of its plugin and its own.
+ */
+pub fn split_qualified_id(id: &str) -> Result<(&str, &str), PluginError> {
+    match id.split_once(ID_SEPARATOR) {
+        Some((plugin_id, function_id)) if !plugin_id.is_empty() && !function_id.is_empty() => Ok((plugin_id, function_id)),
+        _ => Err(PluginError::InvalidInput {
+            message: format!("{} is not a function id, which looks like plugin-id{}function-id.", id, ID_SEPARATOR),
+        }),
+    }
+}
+
+/**
+ * Checks that the functions of a manifest are well formed, and compiles the schemas of their parameters.
+ */
+pub fn validate(functions: &mut [PluginFunction]) -> Result<(), PluginError> {
+    for index in 0..functions.len() {
+        let function = &functions[index];
+
+        if function.id.is_empty() {
+            return Err(PluginError::ManifestInvalid {
+                message: format!("Invalid manifest field functions[{}].id: the function {} has no id.", index, function.entry),
+            });
+        }
+
+        if functions[..index].iter().any(|other| other.id == function.id) {
+            return Err(PluginError::ManifestInvalid {
+                message: format!("Invalid manifest field functions[{}].id: function {} is declared more than once.", index, function.id),
+            });
+        }
+
+        if let Err(err) = paths::sanitize_file_name(&function.entry) {
+            return Err(PluginError::ManifestInvalid {
+                message: format!("Invalid manifest field functions[{}].entry: it must be a file in the scripts folder: {}", index, err),
+            });
+        }
+
+        let parameters_schema = match function.parameters {
+            Some(ref parameters) => match JSONSchema::compile(parameters) {
+                Ok(schema) => Some(Arc::new(schema)),
+                Err(err) => {
+                    return Err(PluginError::ManifestInvalid {
+                        message: format!("Invalid manifest field functions[{}].parameters: it is not a valid JSON Schema: {}", index, err),
+                    });
+                }
+            },
+            None => None,
+        };
+        if let Some(ref result) = function.result {
+            if let Err(err) = JSONSchema::compile(result) {
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Invalid manifest field functions[{}].result: it is not a valid JSON Schema: {}", index, err),
+                });
+            }
+        }
+
+        functions[index].parameters_schema = parameters_schema;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn checks_args_against_the_compiled_parameters() {
+        let mut functions: Vec<PluginFunction> = serde_json::from_value(json!([{
+            "id": "summarize",
+            "entry": "summarize.py",
+            "parameters": { "type": "array", "items": [{ "type": "string" }], "minItems": 1 }
+        }]))
+        .unwrap();
+        validate(&mut functions).unwrap();
+
+        assert!(functions[0].check_args(&[json!("text")]).is_ok());
+        assert!(matches!(functions[0].check_args(&[]), Err(PluginError::InvalidInput { .. })));
+        assert!(matches!(functions[0].check_args(&[json!(1)]), Err(PluginError::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let mut functions: Vec<PluginFunction> = serde_json::from_value(json!([{
+            "id": "summarize",
+            "entry": "summarize.py",
+            "parameters": { "type": "list" }
+        }]))
+        .unwrap();
+
+        let err = validate(&mut functions).unwrap_err();
+        assert!(err.to_string().contains("functions[0].parameters"), "{}", err);
+    }
+}
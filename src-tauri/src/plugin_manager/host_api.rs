@@ -0,0 +1,443 @@
+//! The host API lets running scripts call back into Raphael. Every script and daemon is started with:
+//!
+//! - `RAPHAEL_HOST_API`: the address to connect to, e.g. `127.0.0.1:49152`.
+//! - `RAPHAEL_HOST_TOKEN`: identifies the script to the host API. It stops working once the script exits.
+//! - `RAPHAEL_HOST_API_VERSION`: the version of the host API.
+//!
+//! Scripts connect over TCP and send JSON-RPC 2.0 requests, one per line, and get responses the same way. A request
+//! longer than a megabyte closes the connection.
+//! The first request on a connection must be `host.hello`:
+//!
+//! ```json
+//! --> { "jsonrpc": "2.0", "id": 1, "method": "host.hello", "params": { "token": "...", "version": 1 } }
+//! <-- { "jsonrpc": "2.0", "id": 1, "result": { "version": 1, "methods": ["config.get", "notifications.show"] } }
+//! ```
+//!
+//! The methods of version 1 are:
+//!
+//! - `app.info`: the name and version of the app.
+//! - `app.settings`: the app settings, read-only.
+//! - `config.get`: the plugin's own config, `{}` until it is set. It is kept in `config.json` in the plugin's data
+//!   folder.
+//! - `config.set` `{ "config": ... }`: replaces the plugin's own config.
+//! - `notifications.show` `{ "title": "...", "body": "..." }`: shows a notification.
+//! - `dialog.ask` `{ "question": "..." }`: asks the user a yes or no question and returns the answer.
+//! - `plugins.call` `{ "plugin_id": "...", "function": "summarize", "args": [] }`: runs a function of another plugin
+//!   by its id and returns its result. The arguments can be any JSON, see the `calling` module. Which plugins can be
+//!   called is limited, see the `permissions` module.
+//!
+//! A plugin can only call the methods listed in the `host_api` permissions of its manifest. Failed calls get a
+//! JSON-RPC error whose `data` is the `PluginError`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{daemon, execution, paths, ExecutionResult, PluginError};
+use super::permissions::PluginPermissions;
+
+/// The newest version of the host API. Scripts asking for a newer one are refused.
+pub const HOST_API_VERSION: u32 = 1;
+
+/// How long a request can be. Connections sending longer ones are closed.
+const MAX_REQUEST_BYTES: usize = execution::MAX_OUTPUT_BYTES;
+
+/// Every method of the host API, except `host.hello`.
+pub const HOST_METHODS: [&str; 7] = [
+    "app.info",
+    "app.settings",
+    "config.get",
+    "config.set",
+    "notifications.show",
+    "dialog.ask",
+    "plugins.call",
+];
+
+/// The parts of the host API that need the app itself.
+#[async_trait]
+pub trait HostHandler: Send + Sync {
+    fn app_info(&self) -> Value;
+    async fn show_notification(&self, caller: &HostCaller, title: &str, body: &str) -> Result<(), PluginError>;
+    async fn ask(&self, caller: &HostCaller, question: &str) -> Result<bool, PluginError>;
+    async fn call_plugin(&self, caller: &HostCaller, plugin_id: &str, function: &str, args: Vec<Value>) -> Result<ExecutionResult, PluginError>;
+}
+
+/// The plugin a script belongs to, as seen by the host API.
+#[derive(Debug, Clone)]
+pub struct HostCaller {
+    pub plugin_id: String,
+    pub plugin_name: String,
+    pub local_path: Option<String>,
+    pub permissions: PluginPermissions,
+}
+
+pub struct HostApi {
+    address: SocketAddr,
+    /// The running scripts, by token.
+    sessions: Mutex<HashMap<String, HostCaller>>,
+    handler: Box<dyn HostHandler>,
+    settings_path: path::PathBuf,
+}
+
+/// Gives a script access to the host API until it is dropped.
+pub struct HostSession {
+    host_api: Arc<HostApi>,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct HelloParams {
+    token: String,
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct ConfigSetParams {
+    config: Value,
+}
+
+#[derive(Deserialize)]
+struct NotificationParams {
+    title: String,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Deserialize)]
+struct AskParams {
+    question: String,
+}
+
+#[derive(Deserialize)]
+struct PluginCallParams {
+    plugin_id: String,
+    function: String,
+    #[serde(default)]
+    args: Vec<Value>,
+}
+
+impl HostApi {
+    /**
+     * Starts listening for scripts on a free port of the loopback interface.
+     * The app settings are read from `settings.json` in the config directory.
+     */
+    pub async fn start(config_dir: &path::Path, handler: Box<dyn HostHandler>) -> Result<Arc<HostApi>, PluginError> {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(listener) => listener,
+            Err(err) => {
+                return Err(PluginError::Io {
+                    message: format!("Error starting the host API: {}", err),
+                });
+            }
+        };
+
+        let address = match listener.local_addr() {
+            Ok(address) => address,
+            Err(err) => {
+                return Err(PluginError::Io {
+                    message: format!("Error starting the host API: {}", err),
+                });
+            }
+        };
+
+        let host_api = Arc::new(HostApi {
+            address,
+            sessions: Mutex::new(HashMap::new()),
+            handler,
+            settings_path: config_dir.join("settings.json"),
+        });
+
+        tokio::spawn(Arc::clone(&host_api).accept(listener));
+
+        Ok(host_api)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    /**
+     * Lets a script of a plugin use the host API for as long as the returned session is kept.
+     */
+    pub fn open_session(self: &Arc<Self>, caller: HostCaller) -> HostSession {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        self.sessions.lock().unwrap().insert(token.clone(), caller);
+
+        HostSession {
+            host_api: Arc::clone(self),
+            token,
+        }
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(Arc::clone(&self).serve(stream));
+                }
+                Err(err) => println!("Error accepting a host API connection: {}", err),
+            }
+        }
+    }
+
+    /**
+     * Answers the requests of one connection, in order.
+     */
+    async fn serve(self: Arc<Self>, stream: TcpStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        // Set by `host.hello`.
+        let mut token: Option<String> = None;
+
+        loop {
+            let line = match daemon::next_line(&mut reader, MAX_REQUEST_BYTES).await {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(err) => {
+                    println!("Closing host API connection: {}", err);
+                    return;
+                }
+            };
+
+            let response = match serde_json::from_str::<RpcRequest>(&line) {
+                Ok(request) => {
+                    let result = self.handle(&mut token, &request.method, request.params).await;
+
+                    // Notifications don't get an answer.
+                    let id = match request.id {
+                        Some(id) => id,
+                        None => continue,
+                    };
+
+                    match result {
+                        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                        Err(err) => json!({ "jsonrpc": "2.0", "id": id, "error": rpc_error(&request.method, &err) }),
+                    }
+                }
+                Err(err) => json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": -32700, "message": format!("Parse error: {}", err) },
+                }),
+            };
+
+            let mut response = serde_json::to_vec(&response).unwrap();
+            response.push(b'\n');
+            if writer.write_all(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn handle(&self, token: &mut Option<String>, method: &str, params: Value) -> Result<Value, PluginError> {
+        if method == "host.hello" {
+            let params: HelloParams = parse_params(params)?;
+
+            if params.version > HOST_API_VERSION {
+                return Err(PluginError::InvalidInput {
+                    message: format!("Host API version {} is not supported, the newest is {}.", params.version, HOST_API_VERSION),
+                });
+            }
+
+            let caller = self.caller(&params.token)?;
+            *token = Some(params.token);
+
+            let methods: Vec<&str> = HOST_METHODS
+                .iter()
+                .copied()
+                .filter(|method| caller.permissions.allows_host_method(method))
+                .collect();
+
+            return Ok(json!({ "version": HOST_API_VERSION, "methods": methods }));
+        }
+
+        // Looked up on every request, so a connection stops working once its script exits.
+        let caller = match token {
+            Some(token) => self.caller(token)?,
+            None => {
+                return Err(PluginError::PermissionDenied {
+                    message: "Call host.hello before any other method.".to_string(),
+                });
+            }
+        };
+
+        if !HOST_METHODS.contains(&method) {
+            return Err(PluginError::NotFound {
+                message: format!("The host API has no method {}.", method),
+            });
+        }
+
+        if !caller.permissions.allows_host_method(method) {
+            return Err(PluginError::PermissionDenied {
+                message: format!("Plugin {} does not have the permission to call {}.", caller.plugin_name, method),
+            });
+        }
+
+        match method {
+            "app.info" => Ok(self.handler.app_info()),
+            "app.settings" => read_json_or_empty(&self.settings_path),
+            "config.get" => read_json_or_empty(&config_path(&caller)?),
+            "config.set" => {
+                let params: ConfigSetParams = parse_params(params)?;
+                write_config(&caller, &params.config)?;
+                Ok(Value::Null)
+            }
+            "notifications.show" => {
+                let params: NotificationParams = parse_params(params)?;
+                self.handler.show_notification(&caller, &params.title, &params.body).await?;
+                Ok(Value::Null)
+            }
+            "dialog.ask" => {
+                let params: AskParams = parse_params(params)?;
+                let answer = self.handler.ask(&caller, &params.question).await?;
+                Ok(Value::Bool(answer))
+            }
+            "plugins.call" => {
+                let params: PluginCallParams = parse_params(params)?;
+                let result = self.handler.call_plugin(&caller, &params.plugin_id, &params.function, params.args).await?;
+                Ok(serde_json::to_value(result).unwrap())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn caller(&self, token: &str) -> Result<HostCaller, PluginError> {
+        match self.sessions.lock().unwrap().get(token) {
+            Some(caller) => Ok(caller.clone()),
+            None => Err(PluginError::PermissionDenied {
+                message: "The host API token is invalid or has expired.".to_string(),
+            }),
+        }
+    }
+}
+
+impl HostSession {
+    /**
+     * The environment variables that tell a script how to reach the host API.
+     */
+    pub fn env(&self) -> Vec<(String, String)> {
+        vec![
+            ("RAPHAEL_HOST_API".to_string(), self.host_api.address.to_string()),
+            ("RAPHAEL_HOST_TOKEN".to_string(), self.token.clone()),
+            ("RAPHAEL_HOST_API_VERSION".to_string(), HOST_API_VERSION.to_string()),
+        ]
+    }
+}
+
+impl Drop for HostSession {
+    fn drop(&mut self) {
+        self.host_api.sessions.lock().unwrap().remove(&self.token);
+    }
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, PluginError> {
+    match serde_json::from_value(params) {
+        Ok(params) => Ok(params),
+        Err(err) => Err(PluginError::InvalidInput {
+            message: format!("Invalid params: {}", err),
+        }),
+    }
+}
+
+fn rpc_error(method: &str, err: &PluginError) -> Value {
+    let code = match err {
+        _ if method != "host.hello" && !HOST_METHODS.contains(&method) => -32601,
+        PluginError::InvalidInput { .. } => -32602,
+        _ => -32000,
+    };
+
+    json!({ "code": code, "message": err.to_string(), "data": err })
+}
+
+/**
+ * Where a plugin's own config is kept. It is in the plugin's data folder, so it survives updates.
+ */
+fn config_path(caller: &HostCaller) -> Result<path::PathBuf, PluginError> {
+    match caller.local_path {
+        Some(ref local_path) => Ok(paths::plugin_data_dir(path::Path::new(local_path)).join("config.json")),
+        None => Err(PluginError::NotFound {
+            message: format!("Plugin {} is not installed locally.", caller.plugin_name),
+        }),
+    }
+}
+
+fn write_config(caller: &HostCaller, config: &Value) -> Result<(), PluginError> {
+    let config_path = config_path(caller)?;
+
+    if let Some(data_dir) = config_path.parent() {
+        if let Err(err) = std::fs::create_dir_all(data_dir) {
+            return Err(PluginError::Io {
+                message: format!("Error creating {}: {}", data_dir.display(), err),
+            });
+        }
+    }
+
+    let serialized_config = serde_json::to_string_pretty(config).unwrap();
+    if let Err(err) = std::fs::write(&config_path, serialized_config) {
+        return Err(PluginError::Io {
+            message: format!("Error writing {}: {}", config_path.display(), err),
+        });
+    }
+
+    Ok(())
+}
+
+fn read_json_or_empty(file_path: &path::Path) -> Result<Value, PluginError> {
+    let contents = match std::fs::read_to_string(file_path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(json!({})),
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(PluginError::Io {
+            message: format!("Error parsing {}: {}", file_path.display(), err),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{install, RemotePlugin};
+
+    #[test]
+    fn config_survives_updates() {
+        let plugins_dir = std::env::temp_dir().join(format!("raphael-plugins-{}", uuid::Uuid::new_v4().simple()));
+        let plugin_dir = plugins_dir.join("example");
+        let remote_plugin = RemotePlugin {
+            info_url: String::new(),
+            info: r#"{ "manifest_version": 2, "id": "example", "name": "Example", "version": "1.0.0" }"#.to_string(),
+            signature: None,
+            files: vec![("scripts/start.py".to_string(), String::new())],
+        };
+        let caller = HostCaller {
+            plugin_id: "example".to_string(),
+            plugin_name: "Example".to_string(),
+            local_path: Some(plugin_dir.to_string_lossy().to_string()),
+            permissions: PluginPermissions::default(),
+        };
+
+        install::stage(&plugins_dir, "example", &remote_plugin).unwrap().install(&plugin_dir).unwrap();
+        write_config(&caller, &json!({ "theme": "dark" })).unwrap();
+        install::stage(&plugins_dir, "example", &remote_plugin).unwrap().replace(&plugin_dir).unwrap().commit();
+
+        let config = read_json_or_empty(&config_path(&caller).unwrap());
+        std::fs::remove_dir_all(&plugins_dir).unwrap();
+        assert_eq!(config.unwrap(), json!({ "theme": "dark" }));
+    }
+}
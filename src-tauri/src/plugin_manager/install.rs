@@ -0,0 +1,254 @@
+//! Plugins are never written straight into their folder. They are staged in a hidden folder next to it,
+//! verified there, then renamed into place, so a failure at any point leaves either the previous state or
+//! the complete new plugin on disk. Renames within the plugins directory stay on the same filesystem,
+//! which is what makes them atomic.
+//!
+//! Updates replace everything in the plugin folder except the plugin's data folder, which is carried over.
+
+use std::{fs, path, process};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{paths, PluginError, RemotePlugin};
+
+const STAGING_PREFIX: &str = ".staging-";
+const BACKUP_PREFIX: &str = ".backup-";
+
+/// A plugin written to its staging folder. The staging folder is removed when this is dropped without being installed.
+pub struct StagedPlugin {
+    staging_dir: path::PathBuf,
+}
+
+/// A plugin folder whose previous contents were swapped out for a staged plugin.
+/// The previous version is kept until the update is either committed or rolled back.
+pub struct ReplacedPlugin {
+    target_dir: path::PathBuf,
+    backup_dir: path::PathBuf,
+}
+
+/**
+ * Writes a downloaded plugin to a new staging folder in the plugins directory.
+ */
+pub fn stage(plugins_dir: &path::Path, plugin_folder_name: &str, remote_plugin: &RemotePlugin) -> Result<StagedPlugin, PluginError> {
+    let staged = StagedPlugin {
+        staging_dir: plugins_dir.join(unique_name(STAGING_PREFIX, plugin_folder_name)),
+    };
+
+    // On error, dropping `staged` cleans up whatever was written.
+    remote_plugin.write_to(&staged.staging_dir)?;
+
+    Ok(staged)
+}
+
+impl StagedPlugin {
+    pub fn path(&self) -> &path::Path {
+        &self.staging_dir
+    }
+
+    /**
+     * Moves the staged plugin into a folder that must not exist yet.
+     */
+    pub fn install(self, target_dir: &path::Path) -> Result<(), PluginError> {
+        if target_dir.exists() {
+            return Err(PluginError::Conflict {
+                message: format!("A plugin is already installed in {}.", target_dir.display()),
+            });
+        }
+
+        rename(&self.staging_dir, target_dir)
+    }
+
+    /**
+     * Swaps an installed plugin for the staged one, carrying over the plugin's data folder.
+     * If anything fails, the installed plugin is left as it was.
+     */
+    pub fn replace(self, target_dir: &path::Path) -> Result<ReplacedPlugin, PluginError> {
+        let backup_dir = match target_dir.file_name().and_then(|name| name.to_str()) {
+            Some(name) => target_dir.with_file_name(unique_name(BACKUP_PREFIX, name)),
+            None => {
+                return Err(PluginError::Io {
+                    message: format!("Error reading plugin path {}.", target_dir.display()),
+                });
+            }
+        };
+
+        // The installed data takes the place of any the new version ships with.
+        let staged_data_dir = paths::plugin_data_dir(&self.staging_dir);
+        if paths::plugin_data_dir(target_dir).exists() && staged_data_dir.exists() {
+            if let Err(err) = fs::remove_dir_all(&staged_data_dir) {
+                return Err(PluginError::Io {
+                    message: format!("Error removing {}: {}", staged_data_dir.display(), err),
+                });
+            }
+        }
+
+        // The data only ever moves between the backup and the plugin folder, never into staging, so that `recover`
+        // can finish an interrupted update from the backup.
+        rename(target_dir, &backup_dir)?;
+
+        if let Err(err) = rename(&self.staging_dir, target_dir) {
+            if let Err(restore_err) = rename(&backup_dir, target_dir) {
+                println!("Error restoring plugin backup {}: {}", backup_dir.display(), restore_err);
+            }
+            return Err(err);
+        }
+
+        let replaced = ReplacedPlugin {
+            target_dir: target_dir.to_path_buf(),
+            backup_dir,
+        };
+
+        if let Err(err) = move_data_dir(&replaced.backup_dir, &replaced.target_dir) {
+            replaced.rollback();
+            return Err(err);
+        }
+
+        Ok(replaced)
+    }
+}
+
+impl Drop for StagedPlugin {
+    fn drop(&mut self) {
+        if self.staging_dir.exists() {
+            if let Err(err) = fs::remove_dir_all(&self.staging_dir) {
+                println!("Error removing staging directory {}: {}", self.staging_dir.display(), err);
+            }
+        }
+    }
+}
+
+impl ReplacedPlugin {
+    /**
+     * Keeps the new version and deletes the previous one.
+     */
+    pub fn commit(self) {
+        if let Err(err) = fs::remove_dir_all(&self.backup_dir) {
+            println!("Error removing plugin backup {}: {}", self.backup_dir.display(), err);
+        }
+    }
+
+    /**
+     * Restores the previous version, along with its data folder.
+     */
+    pub fn rollback(self) {
+        let failed_dir = match self.target_dir.file_name().and_then(|name| name.to_str()) {
+            Some(name) => self.target_dir.with_file_name(unique_name(STAGING_PREFIX, name)),
+            None => return,
+        };
+
+        if let Err(err) = move_data_dir(&self.target_dir, &self.backup_dir) {
+            println!("Error rolling back plugin {}: {}", self.target_dir.display(), err);
+            return;
+        }
+
+        if let Err(err) = rename(&self.target_dir, &failed_dir) {
+            println!("Error rolling back plugin {}: {}", self.target_dir.display(), err);
+            return;
+        }
+
+        if let Err(err) = rename(&self.backup_dir, &self.target_dir) {
+            println!("Error rolling back plugin {}: {}", self.target_dir.display(), err);
+            return;
+        }
+
+        if let Err(err) = fs::remove_dir_all(&failed_dir) {
+            println!("Error removing failed update {}: {}", failed_dir.display(), err);
+        }
+    }
+}
+
+/**
+ * Cleans up after installations that were interrupted, e.g. by the app being killed.
+ * Leftover staging folders are removed, since they never hold a plugin's data. Backups are restored if the update
+ * never completed, or have their data carried over to the new version if it did.
+ */
+pub fn recover(plugins_dir: &path::Path) {
+    let entries = match fs::read_dir(plugins_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let entry_name = entry.file_name();
+        let entry_name = match entry_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if entry_name.starts_with(STAGING_PREFIX) {
+            println!("Removing leftover staging directory {}.", entry_name);
+            if let Err(err) = fs::remove_dir_all(entry.path()) {
+                println!("Error removing staging directory {}: {}", entry_name, err);
+            }
+        } else if let Some(plugin_folder_name) = entry_name.strip_prefix(BACKUP_PREFIX).and_then(original_name) {
+            let target_dir = plugins_dir.join(plugin_folder_name);
+
+            if !target_dir.exists() {
+                println!("Restoring plugin {} from an interrupted update.", plugin_folder_name);
+                if let Err(err) = rename(&entry.path(), &target_dir) {
+                    println!("Error recovering plugin backup {}: {}", entry_name, err);
+                }
+                continue;
+            }
+
+            // The backup is only removed once nothing of the plugin's data is left in it.
+            if let Err(err) = move_data_dir(&entry.path(), &target_dir) {
+                println!("Error recovering plugin backup {}: {}", entry_name, err);
+                continue;
+            }
+
+            if let Err(err) = fs::remove_dir_all(entry.path()) {
+                println!("Error removing plugin backup {}: {}", entry_name, err);
+            }
+        }
+    }
+}
+
+/**
+ * Whether a folder in the plugins directory is used for staging or backups rather than being a plugin.
+ */
+pub fn is_transient(folder_name: &str) -> bool {
+    folder_name.starts_with(STAGING_PREFIX) || folder_name.starts_with(BACKUP_PREFIX)
+}
+
+fn unique_name(prefix: &str, plugin_folder_name: &str) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or_default();
+    format!("{}{}-{}-{}", prefix, plugin_folder_name, process::id(), nanos)
+}
+
+/**
+ * Gets the plugin folder name back from the suffix of a name made by `unique_name`.
+ */
+fn original_name(suffix: &str) -> Option<&str> {
+    let mut parts = suffix.rsplitn(3, '-');
+    parts.next()?;
+    parts.next()?;
+    parts.next()
+}
+
+fn rename(from: &path::Path, to: &path::Path) -> Result<(), PluginError> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(PluginError::Io {
+            message: format!("Error moving {} to {}: {}", from.display(), to.display(), err),
+        }),
+    }
+}
+
+/**
+ * Moves the data folder of a plugin from one of its folders to another, if it has one.
+ */
+fn move_data_dir(from_dir: &path::Path, to_dir: &path::Path) -> Result<(), PluginError> {
+    let from_data_dir = paths::plugin_data_dir(from_dir);
+    if !from_data_dir.exists() {
+        return Ok(());
+    }
+
+    let to_data_dir = paths::plugin_data_dir(to_dir);
+    if to_data_dir.exists() {
+        return Err(PluginError::Conflict {
+            message: format!("Both {} and {} exist.", from_data_dir.display(), to_data_dir.display()),
+        });
+    }
+
+    rename(&from_data_dir, &to_data_dir)
+}
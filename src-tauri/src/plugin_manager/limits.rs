@@ -0,0 +1,174 @@
+//! Resource limits keep a plugin script from starving the rest of the system. The defaults can be changed in the
+//! `plugin_limits` section of the app's `settings.json`, and a plugin can override them in its manifest:
+//!
+//! ```json
+//! { "limits": { "memory": 4096, "cpu_time": 600, "open_files": 1024, "processes": 32, "output": 4194304 } }
+//! ```
+//!
+//! Memory, CPU time, open files and processes are enforced with rlimits on Unix, and a script that runs out of one of
+//! them fails with `PluginError::LimitExceeded`. Output beyond the limit is discarded and the result is marked as
+//! truncated instead. Daemons are meant to run indefinitely, so they have no CPU time limit.
+
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use super::PluginError;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// The most memory a script may address, in megabytes.
+    #[serde(default)]
+    pub memory: Option<u64>,
+    /// How much CPU time a script may use, in seconds.
+    #[serde(default)]
+    pub cpu_time: Option<u64>,
+    /// How many files a script may have open at once.
+    #[serde(default)]
+    pub open_files: Option<u64>,
+    /// How many processes may run as the user, counting the script's threads. Unlimited by default, since it also
+    /// counts every other process of the user.
+    #[serde(default)]
+    pub processes: Option<u64>,
+    /// How many bytes of each of stdout and stderr are kept.
+    #[serde(default)]
+    pub output: Option<u64>,
+}
+
+impl ResourceLimits {
+    /**
+     * The limits used when neither the settings nor the plugin set them.
+     */
+    pub fn builtin() -> ResourceLimits {
+        ResourceLimits {
+            memory: Some(2048),
+            cpu_time: Some(300),
+            open_files: Some(256),
+            processes: None,
+            output: Some(super::execution::MAX_OUTPUT_BYTES as u64),
+        }
+    }
+
+    /**
+     * Reads the default limits from the `plugin_limits` section of `settings.json` in the config directory.
+     */
+    pub fn load_defaults(config_dir: &Path) -> ResourceLimits {
+        let builtin = ResourceLimits::builtin();
+        let settings_path = config_dir.join("settings.json");
+
+        let settings = match std::fs::read_to_string(&settings_path) {
+            Ok(settings) => settings,
+            Err(_) => return builtin,
+        };
+
+        let settings: serde_json::Value = match serde_json::from_str(&settings) {
+            Ok(settings) => settings,
+            Err(err) => {
+                println!("Error parsing {}, using the default plugin limits: {}", settings_path.display(), err);
+                return builtin;
+            }
+        };
+
+        match settings.get("plugin_limits").cloned().map(serde_json::from_value::<ResourceLimits>) {
+            Some(Ok(limits)) => builtin.overridden_by(&limits),
+            Some(Err(err)) => {
+                println!("Error parsing the plugin limits in {}, using the default ones: {}", settings_path.display(), err);
+                builtin
+            }
+            None => builtin,
+        }
+    }
+
+    /**
+     * These limits, with the ones set in `overrides` taking their place.
+     */
+    pub fn overridden_by(&self, overrides: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            memory: overrides.memory.or(self.memory),
+            cpu_time: overrides.cpu_time.or(self.cpu_time),
+            open_files: overrides.open_files.or(self.open_files),
+            processes: overrides.processes.or(self.processes),
+            output: overrides.output.or(self.output),
+        }
+    }
+
+    pub fn output_bytes(&self) -> usize {
+        self.output.map_or(usize::MAX, |output| output as usize)
+    }
+
+    /**
+     * Makes `command` start with these limits.
+     */
+    #[cfg(unix)]
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        let rlimits: Vec<_> = [
+            (libc::RLIMIT_AS, self.memory.map(|memory| memory.saturating_mul(1024 * 1024))),
+            (libc::RLIMIT_CPU, self.cpu_time),
+            (libc::RLIMIT_NOFILE, self.open_files),
+            (libc::RLIMIT_NPROC, self.processes),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| Some((resource, limit?)))
+        .collect();
+
+        if rlimits.is_empty() {
+            return;
+        }
+
+        // SAFETY: The closure runs in the child between fork and exec, so it only makes syscalls and doesn't allocate.
+        unsafe {
+            command.pre_exec(move || {
+                for &(resource, limit) in &rlimits {
+                    // The script gets SIGXCPU at the soft CPU limit, which tells it apart from other kills, and SIGKILL at the hard one.
+                    let max = if resource == libc::RLIMIT_CPU { limit.saturating_add(1) } else { limit };
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: max as libc::rlim_t,
+                    };
+
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _command: &mut tokio::process::Command) {}
+}
+
+/**
+ * Turns the failure of a script into a `LimitExceeded` if it looks like it ran out of one of its limits.
+ * Running out of CPU time is detected from the signal that killed the script, the others from the error it printed.
+ */
+pub fn explain_failure(err: PluginError, limits: &ResourceLimits) -> PluginError {
+    let stderr = match err {
+        PluginError::ScriptFailed { ref stderr, .. } => stderr,
+        _ => return err,
+    };
+
+    let checks: [(&str, Option<u64>, &[&str]); 3] = [
+        ("memory", limits.memory, &["MemoryError", "out of memory", "Cannot allocate memory", "std::bad_alloc"]),
+        ("open_files", limits.open_files, &["Too many open files", "EMFILE"]),
+        ("processes", limits.processes, &["Resource temporarily unavailable", "EAGAIN"]),
+    ];
+
+    for (limit, value, messages) in checks {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if messages.iter().any(|message| stderr.contains(message)) {
+            return PluginError::LimitExceeded {
+                limit: limit.to_string(),
+                message: format!("The script ran out of {} (limit: {}).", limit.replace('_', " "), value),
+            };
+        }
+    }
+
+    err
+}
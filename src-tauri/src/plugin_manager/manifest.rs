@@ -0,0 +1,362 @@
+//! The manifest of a plugin is the `info.json` at the root of its folder:
+//!
+//! ```json
+//! {
+//!     "manifest_version": 2,
+//!     "id": "3f1c2a9e-...",
+//!     "name": "Example",
+//!     "version": "1.2.0",
+//!     "description": "Does things.",
+//!     "timeout": 120,
+//!     "daemon": "daemon.py",
+//!     "calling_convention": "json",
+//!     "permissions": { ... },
+//!     "limits": { ... },
+//!     "functions": [ ... ]
+//! }
+//! ```
+//!
+//! `id`, `name` and `version` are required, and `version` is in semver format. Ids are made of letters, digits, `.`,
+//! `_` and `-`, and must be usable as a folder name, so `.` and `..` are not ids. Unknown fields are refused, so a
+//! misspelled field is reported instead of being ignored. See the `permissions`, `limits`, `calling` and `functions`
+//! modules for their sections.
+//!
+//! Manifests without a `manifest_version` are from before it existed, version 1. They are upgraded to the current
+//! shape when they are read: the fields Raphael used to keep in it at runtime are dropped, unknown fields are ignored
+//! with a warning, and a missing version becomes `0.0.0`. Versions Raphael used to accept but aren't semver, like
+//! `v1.2` or `1.0`, are read as `1.2.0` and `1.0.0`. The file itself is left as it is, since signatures cover it.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{paths, PluginError};
+use super::calling::CallingConvention;
+use super::functions::{self, PluginFunction};
+use super::limits::ResourceLimits;
+use super::permissions::PluginPermissions;
+
+/// The newest manifest version this version of Raphael understands.
+pub const MANIFEST_VERSION: u32 = 2;
+
+/// The script engines, by file extension.
+const ENGINES: [&str; 4] = ["py", "js", "sh", "bash"];
+
+/// The engines a daemon can be written for.
+const DAEMON_ENGINES: [&str; 2] = ["py", "js"];
+
+/// The fields of a version 1 manifest that were only ever set at runtime.
+const V1_RUNTIME_FIELDS: [&str; 4] = ["local_path", "remote_url", "startup_script", "function_scripts"];
+
+/// Every field of a version 1 manifest that is still part of the manifest.
+const V1_FIELDS: [&str; 10] = ["id", "name", "version", "description", "timeout", "daemon", "permissions", "limits", "calling_convention", "functions"];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PluginManifest {
+    pub manifest_version: u32,
+    /// The id of the plugin. Randomly generated UUID.
+    pub id: String,
+    pub name: String,
+    /// The version of the plugin in semver format.
+    pub version: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// How long the plugin's scripts may run, in seconds, before they are killed.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// The script in `scripts` that runs as the plugin's daemon. See the `daemon` module.
+    #[serde(default)]
+    pub daemon: Option<String>,
+    #[serde(default)]
+    pub calling_convention: CallingConvention,
+    #[serde(default)]
+    pub permissions: PluginPermissions,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    #[serde(default)]
+    pub functions: Vec<PluginFunction>,
+}
+
+/**
+ * Reads a manifest, upgrading it to the current version if needed, and checks that it is valid.
+ */
+pub fn parse(contents: &str) -> Result<PluginManifest, PluginError> {
+    let manifest: Value = match serde_json::from_str(contents) {
+        Ok(manifest) => manifest,
+        Err(err) => return Err(invalid("", &format!("not valid JSON: {}", err))),
+    };
+
+    let mut manifest = match manifest {
+        Value::Object(manifest) => manifest,
+        _ => return Err(invalid("", "must be a JSON object")),
+    };
+
+    let manifest_version = match manifest.get("manifest_version") {
+        None => 1,
+        Some(version) => match version.as_u64() {
+            Some(version) => version,
+            None => return Err(invalid("manifest_version", "must be a whole number")),
+        },
+    };
+
+    if manifest_version == 1 {
+        upgrade_v1(&mut manifest);
+    } else if manifest_version != MANIFEST_VERSION as u64 {
+        return Err(invalid("manifest_version", &format!("version {} is not supported, the newest is {}", manifest_version, MANIFEST_VERSION)));
+    }
+
+    let mut manifest: PluginManifest = match serde_path_to_error::deserialize(Value::Object(manifest)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            let field = err.path().to_string();
+            return Err(invalid(if field == "." { "" } else { &field }, &err.inner().to_string()));
+        }
+    };
+
+    manifest.validate()?;
+    Ok(manifest)
+}
+
+/**
+ * Brings a version 1 manifest to the current shape.
+ */
+fn upgrade_v1(manifest: &mut Map<String, Value>) {
+    let name = manifest.get("name").and_then(Value::as_str).unwrap_or("unnamed").to_string();
+
+    manifest.retain(|field, _| {
+        if V1_RUNTIME_FIELDS.contains(&field.as_str()) {
+            return false;
+        }
+
+        if !V1_FIELDS.contains(&field.as_str()) {
+            println!("Ignoring unknown field {} in the manifest of plugin {}.", field, name);
+            return false;
+        }
+
+        true
+    });
+
+    let version = match manifest.get("version") {
+        None | Some(Value::Null) => Some("0.0.0".to_string()),
+        Some(Value::String(version)) => Some(normalize_version(version)),
+        // Left for the type check to report.
+        Some(_) => None,
+    };
+    if let Some(version) = version {
+        manifest.insert("version".to_string(), Value::from(version));
+    }
+
+    manifest.insert("manifest_version".to_string(), Value::from(MANIFEST_VERSION));
+}
+
+/**
+ * Turns the loose versions version 1 manifests could have, like `v1.2` or `1`, into semver ones like `1.2.0`.
+ * Versions that still aren't semver are left for the semver parser to refuse.
+ */
+pub fn normalize_version(version: &str) -> String {
+    let version = version.trim().trim_start_matches(['v', 'V']);
+
+    // Pre-release and build metadata come after the three numbers.
+    let (numbers, suffix) = match version.find(['-', '+']) {
+        Some(index) => version.split_at(index),
+        None => (version, ""),
+    };
+
+    let mut numbers: Vec<&str> = numbers.split('.').collect();
+    while numbers.len() < 3 {
+        numbers.push("0");
+    }
+
+    format!("{}{}", numbers.join("."), suffix)
+}
+
+impl PluginManifest {
+    /**
+     * Checks what the types of the fields alone don't.
+     */
+    fn validate(&mut self) -> Result<(), PluginError> {
+        if self.id.is_empty() || !self.id.chars().all(|character| character.is_ascii_alphanumeric() || matches!(character, '.' | '_' | '-')) {
+            return Err(invalid("id", &format!("{:?} must be made of letters, digits, `.`, `_` and `-`", self.id)));
+        }
+
+        if paths::sanitize_file_name(&self.id).is_err() {
+            return Err(invalid("id", &format!("{:?} can't be used as a folder name", self.id)));
+        }
+
+        if self.name.trim().is_empty() {
+            return Err(invalid("name", "must not be empty"));
+        }
+
+        if let Err(err) = Version::parse(&self.version) {
+            return Err(invalid("version", &format!("{:?} is not a semver version like 1.2.0: {}", self.version, err)));
+        }
+
+        if self.timeout == Some(0) {
+            return Err(invalid("timeout", "must be at least one second"));
+        }
+
+        if let Some(ref daemon) = self.daemon {
+            if !DAEMON_ENGINES.contains(&engine_of(daemon)) {
+                return Err(invalid("daemon", &format!("{} must be a script ending in one of: {}", daemon, DAEMON_ENGINES.join(", "))));
+            }
+        }
+
+        for (index, function) in self.functions.iter().enumerate() {
+            if !ENGINES.contains(&engine_of(&function.entry)) {
+                return Err(invalid(&format!("functions[{}].entry", index), &format!("{} must be a script ending in one of: {}", function.entry, ENGINES.join(", "))));
+            }
+        }
+
+        self.permissions.validate()?;
+        functions::validate(&mut self.functions)?;
+
+        Ok(())
+    }
+}
+
+/**
+ * The engine of a script, from its file extension.
+ */
+fn engine_of(file_name: &str) -> &str {
+    match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension,
+        None => "",
+    }
+}
+
+fn invalid(field: &str, message: &str) -> PluginError {
+    let message = if field.is_empty() {
+        format!("Invalid manifest: {}.", message)
+    } else {
+        format!("Invalid manifest field {}: {}.", field, message)
+    };
+
+    PluginError::ManifestInvalid { message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"{ "manifest_version": 2, "id": "example", "name": "Example", "version": "1.2.0" }"#;
+
+    fn error_message(contents: &str) -> String {
+        match parse(contents) {
+            Err(PluginError::ManifestInvalid { message }) => message,
+            other => panic!("Expected an invalid manifest, got {:?}", other.map(|manifest| manifest.id)),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_manifest() {
+        let manifest = parse(r#"{
+            "manifest_version": 2,
+            "id": "example",
+            "name": "Example",
+            "version": "1.2.0",
+            "description": "Does things.",
+            "timeout": 120,
+            "daemon": "daemon.py",
+            "permissions": { "network": ["api.example.com"] },
+            "functions": [{ "id": "summarize", "entry": "summarize.py" }]
+        }"#)
+        .unwrap();
+
+        assert_eq!(manifest.manifest_version, MANIFEST_VERSION);
+        assert_eq!(manifest.id, "example");
+        assert_eq!(manifest.version, "1.2.0");
+        assert_eq!(manifest.timeout, Some(120));
+        assert_eq!(manifest.daemon.as_deref(), Some("daemon.py"));
+        assert_eq!(manifest.functions[0].entry, "summarize.py");
+    }
+
+    #[test]
+    fn rejects_invalid_fields_with_their_path() {
+        for (field, value) in [
+            ("timout", serde_json::json!(1)),
+            ("manifest_version", serde_json::json!(3)),
+            ("manifest_version", serde_json::json!("2")),
+            ("id", serde_json::json!("")),
+            ("id", serde_json::json!("../example")),
+            ("id", serde_json::json!(".")),
+            ("id", serde_json::json!("..")),
+            ("id", serde_json::json!("nul")),
+            ("name", serde_json::json!("  ")),
+            ("version", serde_json::json!("1.2")),
+            ("version", serde_json::json!("v1.2.0")),
+            ("timeout", serde_json::json!(0)),
+            ("daemon", serde_json::json!("daemon.sh")),
+            ("functions", serde_json::json!([{ "id": "summarize", "entry": "summarize.exe" }])),
+            ("permissions", serde_json::json!({ "netwrok": [] })),
+            ("permissions", serde_json::json!({ "filesystem": { "read": ["Documents"] } })),
+            // Runtime fields are only dropped from version 1 manifests.
+            ("local_path", serde_json::json!("/plugins/example")),
+        ] {
+            let mut manifest: Value = serde_json::from_str(MANIFEST).unwrap();
+            manifest[field] = value;
+
+            let message = error_message(&manifest.to_string());
+            assert!(message.starts_with(&format!("Invalid manifest field {}", field)), "{}", message);
+        }
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_json_object() {
+        error_message("not json");
+        error_message("[]");
+    }
+
+    #[test]
+    fn upgrades_v1_manifests() {
+        let manifest = parse(r#"{
+            "id": "example",
+            "name": "Example",
+            "version": "1.2.0",
+            "local_path": "/plugins/example",
+            "startup_script": "startup.py",
+            "favourite_colour": "green"
+        }"#)
+        .unwrap();
+
+        assert_eq!(manifest.manifest_version, MANIFEST_VERSION);
+        assert_eq!(manifest.id, "example");
+        assert_eq!(manifest.version, "1.2.0");
+    }
+
+    #[test]
+    fn gives_v1_manifests_without_a_version_0_0_0() {
+        for version in [None, Some(Value::Null)] {
+            let mut manifest = serde_json::json!({ "id": "example", "name": "Example" });
+            if let Some(version) = version {
+                manifest["version"] = version;
+            }
+
+            assert_eq!(parse(&manifest.to_string()).unwrap().version, "0.0.0");
+        }
+    }
+
+    #[test]
+    fn normalizes_v1_versions() {
+        for (version, normalized) in [
+            ("v1.2", "1.2.0"),
+            ("1", "1.0.0"),
+            (" 1.0-beta ", "1.0.0-beta"),
+            ("2.3.4+build", "2.3.4+build"),
+        ] {
+            let manifest = serde_json::json!({ "id": "example", "name": "Example", "version": version });
+
+            assert_eq!(parse(&manifest.to_string()).unwrap().version, normalized);
+        }
+    }
+
+    #[test]
+    fn rejects_v1_versions_that_are_not_semver() {
+        for version in [serde_json::json!("latest"), serde_json::json!("1.x"), serde_json::json!(1.2)] {
+            let manifest = serde_json::json!({ "id": "example", "name": "Example", "version": version });
+
+            let message = error_message(&manifest.to_string());
+            assert!(message.starts_with("Invalid manifest field version"), "{}", message);
+        }
+    }
+}
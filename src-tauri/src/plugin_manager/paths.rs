@@ -0,0 +1,123 @@
+//! Every name that comes from a source ends up in a path under the plugins directory.
+//! These checks make sure such names can only ever point inside the folder they are joined to.
+
+use std::path::{Path, PathBuf};
+
+use super::PluginError;
+use super::install;
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/**
+ * Checks that a remote-provided name is a single, ordinary path component.
+ */
+pub fn sanitize_file_name(name: &str) -> Result<&str, PluginError> {
+    match unsafe_reason(name) {
+        Some(reason) => Err(PluginError::UnsafePath {
+            message: format!("Rejected unsafe name {:?}: {}.", name, reason),
+        }),
+        None => Ok(name),
+    }
+}
+
+/**
+ * Checks that a remote-provided path is relative and made only of ordinary components, e.g. `scripts/start.py`.
+ */
+pub fn sanitize_relative_path(path: &str) -> Result<&str, PluginError> {
+    if path.starts_with('/') || path.starts_with('\\') {
+        return Err(PluginError::UnsafePath {
+            message: format!("Rejected unsafe path {:?}: it is absolute.", path),
+        });
+    }
+
+    for component in path.split('/') {
+        if let Some(reason) = unsafe_reason(component) {
+            return Err(PluginError::UnsafePath {
+                message: format!("Rejected unsafe path {:?}: {:?} is not allowed because {}.", path, component, reason),
+            });
+        }
+    }
+
+    Ok(path)
+}
+
+/**
+ * Explains why a path component is unsafe, if it is.
+ */
+fn unsafe_reason(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        Some("it is empty")
+    } else if name == "." || name == ".." {
+        Some("it refers to a parent or current directory")
+    } else if name.contains('/') || name.contains('\\') {
+        Some("it contains a path separator")
+    } else if name.contains(':') {
+        Some("it contains a drive or stream separator")
+    } else if name.chars().any(|character| character.is_control()) {
+        Some("it contains control characters")
+    } else if name.ends_with('.') || name.ends_with(' ') {
+        Some("it ends with a dot or a space")
+    } else if is_reserved(name) {
+        Some("it is a reserved device name")
+    } else if install::is_transient(name) {
+        Some("it collides with the names used during installation")
+    } else {
+        None
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+/**
+ * Where a plugin keeps its own data. It is the only folder that is kept when the plugin is updated.
+ */
+pub fn plugin_data_dir(plugin_dir: &Path) -> PathBuf {
+    plugin_dir.join("data")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_ordinary_file_names() {
+        for name in ["start.py", "my-plugin", "summarize_v2.js", ".hidden", "console.txt"] {
+            assert!(sanitize_file_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_unsafe_file_names() {
+        for name in [
+            "", ".", "..", "../evil", "scripts/start.py", "scripts\\start.py", "C:evil", "file::$DATA", "evil\n",
+            "trailing.", "trailing ", "CON", "nul.txt", "Lpt1", ".staging-plugin", ".backup-plugin",
+        ] {
+            assert!(sanitize_file_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn accepts_relative_paths() {
+        for path in ["info.json", "scripts/start.py", "lib/helpers/text.py"] {
+            assert!(sanitize_relative_path(path).is_ok(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        for path in [
+            "/etc/passwd", "\\Windows\\System32", "../info.json", "scripts/../../evil.py", "scripts/./start.py",
+            "scripts//start.py", "scripts/", "C:/evil.py", "scripts\\..\\evil.py", "scripts/aux/start.py",
+        ] {
+            assert!(sanitize_relative_path(path).is_err(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Plugins declare what they need in the `permissions` section of their manifest:
+//!
+//! ```json
+//! {
+//!     "permissions": {
+//!         "filesystem": { "read": ["~/Documents"], "write": ["~/Documents/Summaries"] },
+//!         "network": ["api.example.com"],
+//!         "subprocess": false,
+//!         "shell": false,
+//!         "host_api": ["notifications.show", "config.get", "config.set"]
+//!     }
+//! }
+//! ```
+//!
+//! Filesystem paths are absolute or start with `~/` for the home directory, and cover everything below them. Being
+//! allowed to write to a path also allows reading it. Network hosts are matched exactly, `*` allows any host. A plugin
+//! always has access to its own folder. Shell scripts need `shell`, and `subprocess` too if they run other programs.
+//!
+//! `plugins.call` lets a plugin call the functions of other plugins, and `plugins.call:<plugin id>` only those of one
+//! plugin. Either way, it can only call plugins whose permissions it has itself, so that it can't do through them what
+//! it isn't allowed to.
+//!
+//! The user approves the declared permissions when a plugin is installed, and again when an update declares more than
+//! was approved. Plugins are not run until everything they declare is approved, and anything that is not declared is
+//! refused.
+
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use super::PluginError;
+use super::host_api::HOST_METHODS;
+
+/// The host API method that can be limited to a single plugin, as `plugins.call:<plugin id>`.
+const PLUGINS_CALL: &str = "plugins.call";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginPermissions {
+    #[serde(default)]
+    pub filesystem: FilesystemPermissions,
+    /// The hosts the plugin may connect to.
+    #[serde(default)]
+    pub network: Vec<String>,
+    /// Whether the plugin may start other programs.
+    #[serde(default)]
+    pub subprocess: bool,
+    /// Whether the plugin may run shell scripts. Each one is also shown to the user before it first runs.
+    #[serde(default)]
+    pub shell: bool,
+    /// The host API methods the plugin may call, e.g. `notifications.show`.
+    #[serde(default)]
+    pub host_api: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilesystemPermissions {
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+impl PluginPermissions {
+    pub fn allows_host_method(&self, method: &str) -> bool {
+        self.host_api.iter().any(|allowed| host_method_of(allowed) == method)
+    }
+
+    /**
+     * Whether the plugin may call the functions of a plugin through the host API.
+     */
+    pub fn allows_calling_plugin(&self, plugin_id: &str) -> bool {
+        self.host_api.iter().any(|allowed| allowed == PLUGINS_CALL || scope_of(allowed) == Some(plugin_id))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PluginPermissions::default()
+    }
+
+    /**
+     * Checks that the permissions of a manifest are well formed.
+     */
+    pub fn validate(&self) -> Result<(), PluginError> {
+        for path in self.filesystem.read.iter().chain(self.filesystem.write.iter()) {
+            let relative_to_home = path.strip_prefix("~/").unwrap_or(path);
+            let is_absolute = path.starts_with("~/") || Path::new(path).is_absolute();
+
+            if !is_absolute || Path::new(relative_to_home).components().any(|component| component == std::path::Component::ParentDir) {
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Invalid manifest field permissions.filesystem: {} must be an absolute path or start with ~/, without any `..`.", path),
+                });
+            }
+        }
+
+        for host in &self.network {
+            if host.is_empty() || host.contains("://") || host.contains('/') {
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Invalid manifest field permissions.network: {} must be a host name, like api.example.com.", host),
+                });
+            }
+        }
+
+        for permission in &self.host_api {
+            let method = host_method_of(permission);
+            if !HOST_METHODS.contains(&method) {
+                return Err(PluginError::ManifestInvalid {
+                    message: format!("Invalid manifest field permissions.host_api: the host API has no method {}.", method),
+                });
+            }
+
+            if let Some(scope) = scope_of(permission) {
+                if method != PLUGINS_CALL || scope.is_empty() {
+                    return Err(PluginError::ManifestInvalid {
+                        message: format!("Invalid manifest field permissions.host_api: {} is not a method, only {}:<plugin id> can name a plugin.", permission, PLUGINS_CALL),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * The permissions that are requested here but not covered by `granted`. Empty if everything was granted.
+     */
+    pub fn missing_from(&self, granted: &PluginPermissions) -> PluginPermissions {
+        let granted_readable: Vec<&String> = granted.filesystem.read.iter().chain(granted.filesystem.write.iter()).collect();
+
+        PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: self.filesystem.read
+                    .iter()
+                    .filter(|path| !granted_readable.iter().any(|granted| path_covers(granted, path)))
+                    .cloned()
+                    .collect(),
+                write: self.filesystem.write
+                    .iter()
+                    .filter(|path| !granted.filesystem.write.iter().any(|granted| path_covers(granted, path)))
+                    .cloned()
+                    .collect(),
+            },
+            network: self.network
+                .iter()
+                .filter(|host| !granted.network.iter().any(|granted| granted == "*" || granted.eq_ignore_ascii_case(host)))
+                .cloned()
+                .collect(),
+            subprocess: self.subprocess && !granted.subprocess,
+            shell: self.shell && !granted.shell,
+            host_api: self.host_api
+                .iter()
+                .filter(|permission| !granted.host_api.iter().any(|granted| host_permission_covers(granted, permission)))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/**
+ * The host API method of a `host_api` permission, e.g. `plugins.call` for `plugins.call:3f1c...`.
+ */
+fn host_method_of(permission: &str) -> &str {
+    match permission.split_once(':') {
+        Some((method, _)) => method,
+        None => permission,
+    }
+}
+
+fn scope_of(permission: &str) -> Option<&str> {
+    permission.split_once(':').map(|(_, scope)| scope)
+}
+
+/**
+ * Whether a granted `host_api` permission is the requested one, or `plugins.call` for any `plugins.call:<plugin id>`.
+ */
+fn host_permission_covers(granted: &str, requested: &str) -> bool {
+    granted == requested || (granted == PLUGINS_CALL && host_method_of(requested) == PLUGINS_CALL)
+}
+
+/**
+ * Whether a granted path is the requested path or one of its parents.
+ */
+fn path_covers(granted: &str, requested: &str) -> bool {
+    Path::new(requested).starts_with(Path::new(granted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_missing_when_everything_was_granted() {
+        let requested = PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: vec!["~/Documents".to_string()],
+                write: vec!["~/Notes".to_string()],
+            },
+            network: vec!["api.example.com".to_string()],
+            subprocess: true,
+            shell: true,
+            host_api: vec!["notifications.show".to_string()],
+        };
+
+        assert!(requested.missing_from(&requested).is_empty());
+    }
+
+    #[test]
+    fn everything_is_missing_when_nothing_was_granted() {
+        let requested = PluginPermissions {
+            network: vec!["api.example.com".to_string()],
+            subprocess: true,
+            ..Default::default()
+        };
+
+        assert_eq!(requested.missing_from(&PluginPermissions::default()), requested);
+    }
+
+    #[test]
+    fn granted_parent_paths_cover_children() {
+        let requested = PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: vec!["~/Documents/Work".to_string()],
+                write: vec!["/tmp/out".to_string()],
+            },
+            ..Default::default()
+        };
+        let granted = PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: vec![],
+                write: vec!["~/Documents".to_string(), "/tmp".to_string()],
+            },
+            ..Default::default()
+        };
+
+        assert!(requested.missing_from(&granted).is_empty());
+    }
+
+    #[test]
+    fn reading_does_not_cover_writing_or_sibling_paths() {
+        let requested = PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: vec!["~/Documents2".to_string()],
+                write: vec!["~/Documents".to_string()],
+            },
+            ..Default::default()
+        };
+        let granted = PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: vec!["~/Documents".to_string()],
+                write: vec![],
+            },
+            ..Default::default()
+        };
+
+        let missing = requested.missing_from(&granted);
+        assert_eq!(missing.filesystem.read, vec!["~/Documents2"]);
+        assert_eq!(missing.filesystem.write, vec!["~/Documents"]);
+    }
+
+    #[test]
+    fn network_hosts_match_exactly_unless_any_host_was_granted() {
+        let requested = PluginPermissions {
+            network: vec!["API.example.com".to_string(), "evil.example.com".to_string()],
+            ..Default::default()
+        };
+
+        let granted = PluginPermissions { network: vec!["api.example.com".to_string()], ..Default::default() };
+        assert_eq!(requested.missing_from(&granted).network, vec!["evil.example.com"]);
+
+        let granted = PluginPermissions { network: vec!["*".to_string()], ..Default::default() };
+        assert!(requested.missing_from(&granted).network.is_empty());
+    }
+
+    #[test]
+    fn flags_are_missing_only_when_requested_and_not_granted() {
+        let requested = PluginPermissions { subprocess: true, shell: true, ..Default::default() };
+        let granted = PluginPermissions { subprocess: true, ..Default::default() };
+
+        let missing = requested.missing_from(&granted);
+        assert!(!missing.subprocess);
+        assert!(missing.shell);
+    }
+
+    #[test]
+    fn any_plugin_call_covers_scoped_ones() {
+        let scoped = PluginPermissions {
+            host_api: vec!["plugins.call:summarizer".to_string(), "config.get".to_string()],
+            ..Default::default()
+        };
+        let any = PluginPermissions { host_api: vec!["plugins.call".to_string()], ..Default::default() };
+
+        assert_eq!(scoped.missing_from(&any).host_api, vec!["config.get"]);
+        assert_eq!(any.missing_from(&scoped).host_api, vec!["plugins.call"]);
+    }
+
+    #[test]
+    fn scoped_plugin_calls_only_allow_their_plugin() {
+        let scoped = PluginPermissions { host_api: vec!["plugins.call:summarizer".to_string()], ..Default::default() };
+        let any = PluginPermissions { host_api: vec!["plugins.call".to_string()], ..Default::default() };
+
+        assert!(scoped.allows_host_method("plugins.call"));
+        assert!(scoped.allows_calling_plugin("summarizer"));
+        assert!(!scoped.allows_calling_plugin("shell-runner"));
+        assert!(any.allows_calling_plugin("shell-runner"));
+        assert!(!PluginPermissions::default().allows_calling_plugin("summarizer"));
+    }
+
+    #[test]
+    fn accepts_valid_permissions() {
+        let valid = PluginPermissions {
+            filesystem: FilesystemPermissions {
+                read: vec!["~/Documents".to_string(), "/tmp".to_string()],
+                write: vec![],
+            },
+            network: vec!["api.example.com".to_string(), "*".to_string()],
+            host_api: vec!["config.get".to_string(), "plugins.call".to_string(), "plugins.call:summarizer".to_string()],
+            ..Default::default()
+        };
+
+        assert!(valid.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_permissions() {
+        for invalid in [
+            r#"{ "filesystem": { "read": ["Documents"] } }"#,
+            r#"{ "filesystem": { "write": ["~/Documents/../.ssh"] } }"#,
+            r#"{ "network": ["https://api.example.com"] }"#,
+            r#"{ "network": [""] }"#,
+            r#"{ "host_api": ["files.delete"] }"#,
+            r#"{ "host_api": ["config.get:other-plugin"] }"#,
+            r#"{ "host_api": ["plugins.call:"] }"#,
+        ] {
+            let permissions: PluginPermissions = serde_json::from_str(invalid).unwrap();
+            let err = permissions.validate().unwrap_err();
+            assert!(matches!(err, PluginError::ManifestInvalid { .. }), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(serde_json::from_str::<PluginPermissions>(r#"{ "netwrok": ["api.example.com"] }"#).is_err());
+        assert!(serde_json::from_str::<PluginPermissions>(r#"{ "filesystem": { "exec": ["/bin"] } }"#).is_err());
+        assert!(serde_json::from_str::<PluginPermissions>(r#"{ "clipboard": true }"#).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! The registry index is a single `index.json` a source can publish at its root to describe every plugin it hosts.
+//! Reading it costs one request, instead of crawling every plugin folder through the source's API.
+//!
+//! ```json
+//! {
+//!     "format_version": 1,
+//!     "plugins": [
+//!         {
+//!             "id": "3f1c...",
+//!             "name": "Example",
+//!             "version": "1.2.0",
+//!             "description": "Does things.",
+//!             "folder": "example",
+//!             "functions": [{ "id": "summarize", "entry": "summarize.py", "parameters": { "type": "array" } }],
+//!             "files": [
+//!                 { "path": "info.json", "sha256": "9a0b..." },
+//!                 { "path": "scripts/start.py", "sha256": "77e1...", "url": "https://cdn.example.com/start.py" }
+//!             ]
+//!         }
+//!     ]
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::PluginError;
+use super::functions::PluginFunction;
+
+/// The newest index format this version of Raphael understands.
+pub const REGISTRY_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegistryIndex {
+    pub format_version: u32,
+    pub plugins: Vec<RegistryPlugin>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegistryPlugin {
+    pub id: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    /// The folder of the plugin inside the source's `plugins` folder. Also used as the local folder name.
+    pub folder: String,
+    /// Every file of the plugin, `info.json` included.
+    pub files: Vec<RegistryFile>,
+    /// The functions the plugin declares in its manifest, so the catalog can show them. See the `functions` module.
+    #[serde(default)]
+    pub functions: Vec<PluginFunction>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegistryFile {
+    /// The path of the file relative to the plugin folder, e.g. `scripts/start.py`.
+    pub path: String,
+    /// The hex-encoded SHA-256 hash of the file contents.
+    pub sha256: String,
+    /// Where to download the file from. When omitted, the file is read from the plugin folder in the source.
+    pub url: Option<String>,
+}
+
+/**
+ * Computes the hex-encoded SHA-256 hash of some contents.
+ */
+pub fn sha256_hex(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/**
+ * Checks that downloaded contents match the hash announced for them.
+ */
+pub fn verify_sha256(file_path: &str, contents: &[u8], expected: &str) -> Result<(), PluginError> {
+    let actual = sha256_hex(contents);
+
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(PluginError::VerificationFailed {
+            message: format!("Checksum mismatch for {}: expected {}, got {}.", file_path, expected, actual),
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//! Everything plugin scripts need while they run, kept together so it can be handed around as one.
+
+use std::sync::Arc;
+
+use super::{Daemons, Executions};
+use super::host_api::HostApi;
+use super::limits::ResourceLimits;
+
+pub struct ScriptRuntime {
+    pub executions: Executions,
+    pub daemons: Daemons,
+    pub host_api: Arc<HostApi>,
+    /// The resource limits of plugins that don't set their own, from the app settings.
+    pub limits: ResourceLimits,
+    /// The version of the app, as told to scripts using the `json` calling convention.
+    pub app_version: String,
+}
+
+impl ScriptRuntime {
+    pub fn new(executions: Executions, host_api: Arc<HostApi>, limits: ResourceLimits, app_version: String) -> ScriptRuntime {
+        ScriptRuntime {
+            executions,
+            daemons: Daemons::new(Arc::clone(&host_api)),
+            host_api,
+            limits,
+            app_version,
+        }
+    }
+
+    /**
+     * Kills every running script and stops every daemon. Called when the app exits.
+     */
+    pub async fn shutdown(&self) {
+        self.executions.shutdown();
+        self.daemons.shutdown().await;
+    }
+}
@@ -0,0 +1,329 @@
+//! On Linux, plugin scripts and daemons run sandboxed so that they can only do what their permissions allow:
+//!
+//! - A Landlock ruleset limits the filesystem to the system directories interpreters need, the plugin's own folder,
+//!   which it can only write to in its data folder, and the paths it was granted. Network connections are refused
+//!   unless the plugin was granted a network permission, except to the host API. Landlock works on ports rather
+//!   than hosts, so any network permission allows connecting to any host.
+//! - A seccomp filter blocks syscalls no script needs, like `ptrace` or `mount`, and starting other programs unless
+//!   the plugin was granted `subprocess`.
+//!
+//! Without `subprocess`, a script can still replace itself with another program through `execve`. The filters are
+//! installed before the interpreter is started, so they can't refuse `execve` without refusing the interpreter too.
+//! The new program stays in the same process and is bound by the same Landlock ruleset and seccomp filters, which
+//! are inherited across `execve`, and `no_new_privs` keeps setuid programs from gaining privileges. It can't do
+//! anything the script couldn't already do itself, since interpreters can run native code anyway, e.g. with `ctypes`.
+//!
+//! Kernels without Landlock, or with an older version of it, only get part of the sandbox, which is logged once.
+//! On other platforms, scripts are not sandboxed.
+
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use super::{paths, PluginError};
+use super::host_api::HostCaller;
+
+/// The system directories interpreters are read and run from.
+#[cfg(target_os = "linux")]
+const SYSTEM_DIRS: [&str; 9] = ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/nix/store"];
+
+/// The files below `/proc` and `/sys` interpreters read to size themselves. The rest would expose other processes.
+#[cfg(target_os = "linux")]
+const SYSTEM_INFO_FILES: [&str; 4] = ["/proc/cpuinfo", "/proc/meminfo", "/proc/stat", "/sys/devices/system/cpu"];
+
+/// The devices scripts may write to.
+#[cfg(target_os = "linux")]
+const WRITABLE_DEVICES: [&str; 3] = ["/dev/null", "/dev/zero", "/dev/tty"];
+
+/// What a plugin process may access.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+    network: bool,
+    subprocess: bool,
+    /// The host API is reachable even without network permissions.
+    host_api_port: u16,
+}
+
+static SUPPORT_CHECK: Once = Once::new();
+
+impl Sandbox {
+    pub fn new(caller: &HostCaller, host_api_port: u16) -> Sandbox {
+        let permissions = &caller.permissions;
+
+        let mut read: Vec<PathBuf> = permissions.filesystem.read.iter().map(|path| expand_home(path)).collect();
+        let mut write: Vec<PathBuf> = permissions.filesystem.write.iter().map(|path| expand_home(path)).collect();
+        if let Some(ref local_path) = caller.local_path {
+            read.push(PathBuf::from(local_path));
+            write.push(paths::plugin_data_dir(Path::new(local_path)));
+        }
+
+        Sandbox {
+            read,
+            write,
+            network: !permissions.network.is_empty(),
+            subprocess: permissions.subprocess,
+            host_api_port,
+        }
+    }
+
+    /**
+     * Makes `command` start sandboxed. `program` is the interpreter it runs, which may be read and run from where it
+     * is installed.
+     */
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, command: &mut tokio::process::Command, program: &str) -> Result<(), PluginError> {
+        use landlock::{Access, AccessFs, AccessNet, NetPort, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr, ABI};
+
+        SUPPORT_CHECK.call_once(log_support);
+
+        let abi = ABI::V4;
+        let read_access = AccessFs::from_read(abi);
+        let write_access = AccessFs::from_read(abi) | AccessFs::from_write(abi);
+
+        let mut read: Vec<PathBuf> = SYSTEM_DIRS.iter().chain(SYSTEM_INFO_FILES.iter()).map(PathBuf::from).collect();
+        read.extend(interpreter_dir(program));
+        read.extend(self.read.iter().cloned());
+
+        let mut write: Vec<PathBuf> = WRITABLE_DEVICES.iter().map(PathBuf::from).collect();
+        write.extend(self.write.iter().cloned());
+
+        let build_ruleset = || -> Result<_, landlock::RulesetError> {
+            let mut ruleset = Ruleset::default().handle_access(AccessFs::from_all(abi))?;
+            if !self.network {
+                ruleset = ruleset.handle_access(AccessNet::from_all(abi))?;
+            }
+
+            let mut ruleset = ruleset.create()?;
+
+            for (paths, access) in [(&read, read_access), (&write, write_access)] {
+                for path in paths {
+                    // Paths that don't exist can't be accessed anyway.
+                    let path_fd = match PathFd::new(path) {
+                        Ok(path_fd) => path_fd,
+                        Err(_) => continue,
+                    };
+
+                    // Files only support the access rights of files.
+                    let access = if path.is_dir() { access } else { access & AccessFs::from_file(abi) };
+                    ruleset = ruleset.add_rule(PathBeneath::new(path_fd, access))?;
+                }
+            }
+
+            if !self.network {
+                ruleset = ruleset.add_rule(NetPort::new(self.host_api_port, AccessNet::ConnectTcp))?;
+            }
+
+            Ok(ruleset)
+        };
+
+        let ruleset = match build_ruleset() {
+            Ok(ruleset) => ruleset,
+            Err(err) => {
+                return Err(PluginError::Io {
+                    message: format!("Error setting up the sandbox: {}", err),
+                });
+            }
+        };
+
+        let filters = syscall_filters(self.subprocess)?;
+
+        let mut ruleset = Some(ruleset);
+        // SAFETY: The closure runs in the child between fork and exec, so it only makes syscalls and doesn't allocate.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(ruleset) = ruleset.take() {
+                    if ruleset.restrict_self().is_err() {
+                        return Err(std::io::Error::from_raw_os_error(libc::EPERM));
+                    }
+                }
+
+                for filter in &filters {
+                    if seccompiler::apply_filter(filter).is_err() {
+                        return Err(std::io::Error::from_raw_os_error(libc::EPERM));
+                    }
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _command: &mut tokio::process::Command, _program: &str) -> Result<(), PluginError> {
+        SUPPORT_CHECK.call_once(|| println!("Warning: plugin scripts are not sandboxed on this platform."));
+        Ok(())
+    }
+}
+
+/**
+ * Turns the failure of a sandboxed script into a `PermissionDenied` if it looks like the sandbox stopped it.
+ * Scripts only see a refused syscall, so this goes by the error they printed.
+ */
+pub fn explain_failure(err: PluginError) -> PluginError {
+    if !cfg!(target_os = "linux") {
+        return err;
+    }
+
+    const DENIAL_MESSAGES: [&str; 5] = ["Permission denied", "Operation not permitted", "PermissionError", "EACCES", "EPERM"];
+
+    let stderr = match err {
+        PluginError::ScriptFailed { ref stderr, .. } => stderr,
+        _ => return err,
+    };
+
+    match stderr.lines().rev().find(|line| DENIAL_MESSAGES.iter().any(|message| line.contains(message))) {
+        Some(line) => PluginError::PermissionDenied {
+            message: format!("The sandbox stopped the script from doing something it has no permission for: {}", line.trim()),
+        },
+        None => err,
+    }
+}
+
+/**
+ * Expands a leading `~/` to the home directory.
+ */
+fn expand_home(path: &str) -> PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+
+    match (path.strip_prefix("~/"), home) {
+        (Some(relative_path), Some(home)) => Path::new(&home).join(relative_path),
+        _ => PathBuf::from(path),
+    }
+}
+
+/**
+ * Where an interpreter outside of the system directories is installed, e.g. `~/.nvm/versions/node/v20.11.0`,
+ * so that it can load its own libraries.
+ */
+#[cfg(target_os = "linux")]
+fn interpreter_dir(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    let executable = std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())?;
+    let executable = executable.canonicalize().ok()?;
+
+    let bin_dir = executable.parent()?;
+    let install_dir = bin_dir.parent()?;
+
+    // Never the home directory or the root as a whole.
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    if install_dir.parent().is_none() || Some(install_dir) == home.as_deref() {
+        return Some(bin_dir.to_path_buf());
+    }
+
+    Some(install_dir.to_path_buf())
+}
+
+/**
+ * The seccomp filters of a plugin process. A syscall is refused if any of them refuses it.
+ */
+#[cfg(target_os = "linux")]
+fn syscall_filters(subprocess: bool) -> Result<Vec<seccompiler::BpfProgram>, PluginError> {
+    use std::collections::BTreeMap;
+    use seccompiler::{SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter, SeccompRule, TargetArch};
+
+    // Logged by `log_support`.
+    let target_arch: TargetArch = match std::env::consts::ARCH.try_into() {
+        Ok(target_arch) => target_arch,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut denied: BTreeMap<i64, Vec<SeccompRule>> = [
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_acct,
+        libc::SYS_quotactl,
+        libc::SYS_syslog,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_adjtimex,
+    ]
+    .into_iter()
+    .map(|syscall| (syscall, Vec::new()))
+    .collect();
+
+    // Threads are still allowed, they are clones that share the process. `execve` is allowed, see the module doc.
+    let mut clone3_filter = None;
+    if !subprocess {
+        let new_process = match SeccompCondition::new(0, SeccompCmpArgLen::Qword, SeccompCmpOp::MaskedEq(libc::CLONE_THREAD as u64), 0) {
+            Ok(condition) => condition,
+            Err(err) => return Err(syscall_filter_error(err)),
+        };
+        denied.insert(libc::SYS_clone, vec![SeccompRule::new(vec![new_process]).map_err(syscall_filter_error)?]);
+        #[cfg(target_arch = "x86_64")]
+        {
+            denied.insert(libc::SYS_fork, Vec::new());
+            denied.insert(libc::SYS_vfork, Vec::new());
+        }
+
+        // The flags of clone3 can't be checked, so it is made to look unsupported, which makes libc fall back to clone.
+        clone3_filter = Some(SeccompFilter::new(
+            [(libc::SYS_clone3, Vec::new())].into_iter().collect(),
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::ENOSYS as u32),
+            target_arch,
+        ));
+    }
+
+    let filter = SeccompFilter::new(denied, SeccompAction::Allow, SeccompAction::Errno(libc::EPERM as u32), target_arch);
+
+    let mut filters = Vec::new();
+    for filter in std::iter::once(filter).chain(clone3_filter) {
+        let filter = filter.map_err(syscall_filter_error)?;
+        filters.push(filter.try_into().map_err(syscall_filter_error)?);
+    }
+
+    Ok(filters)
+}
+
+#[cfg(target_os = "linux")]
+fn syscall_filter_error(err: seccompiler::BackendError) -> PluginError {
+    PluginError::Io {
+        message: format!("Error setting up the sandbox: {}", err),
+    }
+}
+
+/**
+ * Logs what the kernel cannot enforce. Landlock's network rules need version 4.
+ */
+#[cfg(target_os = "linux")]
+fn log_support() {
+    if seccompiler::TargetArch::try_from(std::env::consts::ARCH).is_err() {
+        println!("Warning: the syscalls of plugin scripts are not filtered on {}.", std::env::consts::ARCH);
+    }
+
+    // `landlock_create_ruleset` returns the newest supported version with this flag.
+    const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+    let version = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<libc::c_void>(), 0, LANDLOCK_CREATE_RULESET_VERSION) };
+
+    if version < 1 {
+        println!("Warning: this kernel does not support Landlock, so plugin scripts have access to every file and the network. Only their syscalls are filtered.");
+    } else if version < 4 {
+        println!("Warning: this kernel supports Landlock version {}, so plugin scripts have access to the network regardless of their permissions.", version);
+    }
+}
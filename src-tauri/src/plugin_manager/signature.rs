@@ -0,0 +1,281 @@
+//! Signed plugins ship a `signature.json` next to their `info.json`:
+//!
+//! ```json
+//! {
+//!     "files": {
+//!         "info.json": "9a0b...",
+//!         "scripts/start.py": "77e1..."
+//!     },
+//!     "signature": "base64 ed25519 signature"
+//! }
+//! ```
+//!
+//! `files` maps the path of every file of the plugin to its hex-encoded SHA-256 hash.
+//! The signature is made over `files` serialized as compact JSON with its keys sorted, which is what
+//! `serde_json` produces for a `BTreeMap`.
+
+use std::collections::BTreeMap;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use super::PluginError;
+use super::registry::verify_sha256;
+
+/// The name of the signature file in a plugin folder.
+pub const SIGNATURE_FILE_NAME: &str = "signature.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PluginSignature {
+    pub files: BTreeMap<String, String>,
+    pub signature: String,
+}
+
+impl PluginSignature {
+    /**
+     * The bytes the signature is made over.
+     */
+    fn signed_message(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.files).unwrap()
+    }
+
+    /**
+     * Checks the signature against every trusted key until one of them matches.
+     */
+    fn verify_signature(&self, trusted_keys: &[String]) -> Result<(), String> {
+        let signature = match BASE64.decode(self.signature.trim()) {
+            Ok(signature) => signature,
+            Err(err) => return Err(format!("the signature is not valid base64: {}", err)),
+        };
+
+        let signature = match Signature::from_slice(&signature) {
+            Ok(signature) => signature,
+            Err(err) => return Err(format!("the signature is malformed: {}", err)),
+        };
+
+        let message = self.signed_message();
+
+        for trusted_key in trusted_keys {
+            let verifying_key = match parse_public_key(trusted_key) {
+                Ok(key) => key,
+                Err(err) => {
+                    println!("Ignoring trusted key {}: {}", trusted_key, err);
+                    continue;
+                }
+            };
+
+            if verifying_key.verify_strict(&message, &signature).is_ok() {
+                return Ok(());
+            }
+        }
+
+        Err("the signature does not match any trusted key of the source".to_string())
+    }
+}
+
+/**
+ * Parses a base64-encoded ed25519 public key.
+ */
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey, String> {
+    let key = match BASE64.decode(key.trim()) {
+        Ok(key) => key,
+        Err(err) => return Err(format!("not valid base64: {}", err)),
+    };
+
+    let key: [u8; 32] = match key.try_into() {
+        Ok(key) => key,
+        Err(_) => return Err("an ed25519 public key must be 32 bytes long".to_string()),
+    };
+
+    match VerifyingKey::from_bytes(&key) {
+        Ok(key) => Ok(key),
+        Err(err) => Err(format!("not a valid ed25519 public key: {}", err)),
+    }
+}
+
+/**
+ * Verifies a downloaded plugin against its signature file.
+ *
+ * `files` must contain every file of the plugin except the signature file itself.
+ * Sources with trusted keys only accept signed plugins. Sources without any can still serve signed plugins,
+ * in which case only the hashes are checked since there is nothing to check the signature against.
+ */
+pub fn verify_plugin(plugin_name: &str, files: &[(&str, &[u8])], signature: Option<&str>, trusted_keys: &[String]) -> Result<(), PluginError> {
+    let signature = match signature {
+        Some(signature) => signature,
+        None => {
+            if !trusted_keys.is_empty() {
+                return Err(PluginError::VerificationFailed {
+                    message: format!("Plugin {} is not signed, but its source only accepts signed plugins.", plugin_name),
+                });
+            }
+
+            println!("Plugin {} is not signed.", plugin_name);
+            return Ok(());
+        }
+    };
+
+    let signature: PluginSignature = match serde_json::from_str(signature) {
+        Ok(signature) => signature,
+        Err(err) => {
+            return Err(PluginError::VerificationFailed {
+                message: format!("Error parsing the signature file of plugin {}: {}", plugin_name, err),
+            });
+        }
+    };
+
+    for (file_path, contents) in files {
+        let expected = match signature.files.get(*file_path) {
+            Some(hash) => hash,
+            None => {
+                return Err(PluginError::VerificationFailed {
+                    message: format!("Plugin {} contains {}, which is not covered by its signature.", plugin_name, file_path),
+                });
+            }
+        };
+
+        verify_sha256(file_path, contents, expected)?;
+    }
+
+    for file_path in signature.files.keys() {
+        if !files.iter().any(|(path, _)| path == file_path) {
+            return Err(PluginError::VerificationFailed {
+                message: format!("Plugin {} is missing {}, which is listed in its signature.", plugin_name, file_path),
+            });
+        }
+    }
+
+    if trusted_keys.is_empty() {
+        println!("Plugin {} is signed, but its source has no trusted keys to check the signature against.", plugin_name);
+        return Ok(());
+    }
+
+    if let Err(err) = signature.verify_signature(trusted_keys) {
+        return Err(PluginError::VerificationFailed {
+            message: format!("Signature verification failed for plugin {}: {}.", plugin_name, err),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use super::super::registry::sha256_hex;
+
+    const FILES: [(&str, &[u8]); 2] = [
+        ("info.json", br#"{ "id": "example", "name": "Example", "version": "1.0.0" }"#),
+        ("scripts/start.py", b"print('hello')"),
+    ];
+
+    fn public_key(key: &SigningKey) -> String {
+        BASE64.encode(key.verifying_key().to_bytes())
+    }
+
+    /**
+     * A signature file for `files`, signed with `key`.
+     */
+    fn sign(files: &[(&str, &[u8])], key: &SigningKey) -> String {
+        let mut signature = PluginSignature {
+            files: files.iter().map(|(path, contents)| (path.to_string(), sha256_hex(contents))).collect(),
+            signature: String::new(),
+        };
+        signature.signature = BASE64.encode(key.sign(&signature.signed_message()).to_bytes());
+
+        serde_json::to_string(&signature).unwrap()
+    }
+
+    #[test]
+    fn accepts_plugin_signed_with_trusted_key() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let trusted_keys = [public_key(&SigningKey::from_bytes(&[2; 32])), public_key(&key)];
+
+        assert!(verify_plugin("example", &FILES, Some(&sign(&FILES, &key)), &trusted_keys).is_ok());
+    }
+
+    #[test]
+    fn accepts_unsigned_plugin_from_source_without_trusted_keys() {
+        assert!(verify_plugin("example", &FILES, None, &[]).is_ok());
+    }
+
+    #[test]
+    fn accepts_signed_plugin_with_matching_hashes_from_source_without_trusted_keys() {
+        let signature = sign(&FILES, &SigningKey::from_bytes(&[1; 32]));
+
+        assert!(verify_plugin("example", &FILES, Some(&signature), &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsigned_plugin_from_source_with_trusted_keys() {
+        let trusted_keys = [public_key(&SigningKey::from_bytes(&[1; 32]))];
+        let err = verify_plugin("example", &FILES, None, &trusted_keys).unwrap_err();
+
+        assert!(err.to_string().contains("is not signed"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_signature_from_untrusted_key() {
+        let signature = sign(&FILES, &SigningKey::from_bytes(&[1; 32]));
+        let trusted_keys = [public_key(&SigningKey::from_bytes(&[2; 32]))];
+        let err = verify_plugin("example", &FILES, Some(&signature), &trusted_keys).unwrap_err();
+
+        assert!(err.to_string().contains("does not match any trusted key"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_tampered_file() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let tampered: [(&str, &[u8]); 2] = [FILES[0], ("scripts/start.py", b"import os")];
+        let err = verify_plugin("example", &tampered, Some(&sign(&FILES, &key)), &[public_key(&key)]).unwrap_err();
+
+        assert!(err.to_string().contains("Checksum mismatch for scripts/start.py"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_tampered_hash_list() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut signature: PluginSignature = serde_json::from_str(&sign(&FILES, &key)).unwrap();
+        // Matching hashes for new contents don't help without a new signature.
+        signature.files.insert("scripts/start.py".to_string(), sha256_hex(b"import os"));
+        let signature = serde_json::to_string(&signature).unwrap();
+        let tampered: [(&str, &[u8]); 2] = [FILES[0], ("scripts/start.py", b"import os")];
+        let err = verify_plugin("example", &tampered, Some(&signature), &[public_key(&key)]).unwrap_err();
+
+        assert!(err.to_string().contains("does not match any trusted key"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_file_not_covered_by_signature() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let extra: [(&str, &[u8]); 3] = [FILES[0], FILES[1], ("scripts/extra.py", b"print('extra')")];
+        let err = verify_plugin("example", &extra, Some(&sign(&FILES, &key)), &[public_key(&key)]).unwrap_err();
+
+        assert!(err.to_string().contains("not covered by its signature"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_missing_signed_file() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let err = verify_plugin("example", &FILES[..1], Some(&sign(&FILES, &key)), &[public_key(&key)]).unwrap_err();
+
+        assert!(err.to_string().contains("is missing scripts/start.py"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_malformed_signature_file() {
+        let trusted_keys = [public_key(&SigningKey::from_bytes(&[1; 32]))];
+        let err = verify_plugin("example", &FILES, Some("not json"), &trusted_keys).unwrap_err();
+
+        assert!(err.to_string().contains("Error parsing the signature file"), "{}", err);
+    }
+
+    #[test]
+    fn parses_public_keys() {
+        assert!(parse_public_key(&public_key(&SigningKey::from_bytes(&[1; 32]))).is_ok());
+        assert!(parse_public_key("not base64!").is_err());
+        assert!(parse_public_key(&BASE64.encode([1u8; 16])).is_err());
+    }
+}
@@ -0,0 +1,540 @@
+//! Plugin sources are the places plugins can be downloaded from.
+//! Every backend exposes the same folder/file view of its storage, laid out like the
+//! `Project-Raphael-Plugins` repository: a `plugins` folder containing one folder per plugin.
+
+use std::{fmt, fs, path};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use reqwest;
+
+use super::{paths, PluginError};
+use super::registry::{RegistryIndex, REGISTRY_FORMAT_VERSION};
+
+#[async_trait]
+pub trait PluginSource: fmt::Debug + Send + Sync {
+    /**
+     * A stable identifier for this source, used by the frontend to refer to it.
+     */
+    fn id(&self) -> String;
+
+    /**
+     * The serializable description of this source, as stored in the config.
+     */
+    fn kind(&self) -> PluginSourceKind;
+
+    /**
+     * The URL, or local path, a file of the source is fetched from.
+     */
+    fn get_file_url(&self, path: &str) -> Result<String, PluginError>;
+
+    /**
+     * Lists the entries of a folder in the source.
+     */
+    async fn list_folder(&self, path: &str) -> Result<Vec<RemoteEntry>, PluginError>;
+
+    /**
+     * Downloads the contents of a file in the source.
+     */
+    async fn fetch_file(&self, path: &str) -> Result<String, PluginError> {
+        let file_url = self.get_file_url(path)?;
+        get_text(&file_url).await
+    }
+
+    /**
+     * Downloads the registry index published at the root of the source.
+     * Returns `None` when the source has no usable index, in which case its folders have to be crawled instead.
+     */
+    async fn fetch_index(&self) -> Option<RegistryIndex> {
+        let index = match self.fetch_file("index.json").await {
+            Ok(index) => index,
+            Err(_) => return None,
+        };
+
+        let index: RegistryIndex = match serde_json::from_str(&index) {
+            Ok(index) => index,
+            Err(err) => {
+                println!("Error parsing registry index of source {}: {}", self.id(), err);
+                return None;
+            }
+        };
+
+        if index.format_version > REGISTRY_FORMAT_VERSION {
+            println!("Registry index of source {} uses format version {}, which is newer than the supported version {}.", self.id(), index.format_version, REGISTRY_FORMAT_VERSION);
+            return None;
+        }
+
+        Some(index)
+    }
+}
+
+/// The kind of a plugin source along with its settings.
+/// This is what gets serialized, tagged with `kind`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PluginSourceKind {
+    Github(GitHubSource),
+    Gitea(GiteaSource),
+    Gitlab(GitLabSource),
+    Http(HttpIndexSource),
+    Local(LocalSource),
+}
+
+impl PluginSourceKind {
+    pub fn into_source(self) -> Box<dyn PluginSource> {
+        match self {
+            PluginSourceKind::Github(source) => Box::new(source),
+            PluginSourceKind::Gitea(source) => Box::new(source),
+            PluginSourceKind::Gitlab(source) => Box::new(source),
+            PluginSourceKind::Http(source) => Box::new(source),
+            PluginSourceKind::Local(source) => Box::new(source),
+        }
+    }
+}
+
+/// A source as persisted in `sources.json`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PluginSourceConfig {
+    /// Disabled sources are kept in the config but not queried.
+    pub enabled: bool,
+    /// Base64-encoded ed25519 public keys the plugins of this source must be signed with.
+    /// When empty, unsigned plugins are accepted.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    #[serde(flatten)]
+    pub kind: PluginSourceKind,
+}
+
+impl PluginSourceConfig {
+    pub fn new(kind: PluginSourceKind, trusted_keys: Vec<String>) -> PluginSourceConfig {
+        PluginSourceConfig {
+            enabled: true,
+            trusted_keys,
+            kind,
+        }
+    }
+
+    pub fn id(&self) -> String {
+        self.kind.clone().into_source().id()
+    }
+}
+
+/// An entry of a folder listing returned by a plugin source.
+#[derive(Debug, Deserialize)]
+pub struct RemoteEntry {
+    pub name: String,
+    /// Either `file` or `dir`.
+    #[serde(rename = "type")]
+    pub entry_type: String,
+}
+
+/// A GitHub repository, read through the contents API and `raw.githubusercontent.com`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GitHubSource {
+    username: Option<String>,
+    repository: Option<String>,
+    branch: Option<String>,
+}
+
+impl GitHubSource {
+    pub fn new(username: Option<String>, repository: Option<String>, branch: Option<String>) -> GitHubSource {
+        GitHubSource {
+            username,
+            repository,
+            branch,
+        }
+    }
+
+    pub fn get_folder_url(&self, path: &str) -> Result<String, PluginError> {
+        let username = match self.username {
+            Some(ref username) => username,
+            None => {
+                return Err(PluginError::SourceInvalid {
+                    message: "Username is not set.".to_string(),
+                });
+            }
+        };
+
+        let repository = match self.repository {
+            Some(ref repository) => repository,
+            None => {
+                return Err(PluginError::SourceInvalid {
+                    message: "Repository is not set.".to_string(),
+                });
+            }
+        };
+
+        let branch_param = match self.branch {
+            Some(ref branch) => format!("?ref={}", branch),
+            None => "".to_string()
+        };
+
+        Ok(format!("https://api.github.com/repos/{}/{}/contents/{}{}", username, repository, path, &branch_param))
+    }
+}
+
+#[async_trait]
+impl PluginSource for GitHubSource {
+    fn id(&self) -> String {
+        format!(
+            "github:{}/{}@{}",
+            self.username.as_deref().unwrap_or_default(),
+            self.repository.as_deref().unwrap_or_default(),
+            self.branch.as_deref().unwrap_or_default()
+        )
+    }
+
+    fn kind(&self) -> PluginSourceKind {
+        PluginSourceKind::Github(self.clone())
+    }
+
+    fn get_file_url(&self, path: &str) -> Result<String, PluginError> {
+        let username = match self.username {
+            Some(ref username) => username,
+            None => {
+                return Err(PluginError::SourceInvalid {
+                    message: "Username is not set.".to_string(),
+                });
+            }
+        };
+
+        let repository = match self.repository {
+            Some(ref repository) => repository,
+            None => {
+                return Err(PluginError::SourceInvalid {
+                    message: "Repository is not set.".to_string(),
+                });
+            }
+        };
+
+        let branch = match self.branch {
+            Some(ref branch) => branch,
+            None => {
+                return Err(PluginError::SourceInvalid {
+                    message: "Branch is not set.".to_string(),
+                });
+            }
+        };
+
+        Ok(format!("https://raw.githubusercontent.com/{}/{}/{}/{}", username, repository, branch, path))
+    }
+
+    async fn list_folder(&self, path: &str) -> Result<Vec<RemoteEntry>, PluginError> {
+        let folder_url = self.get_folder_url(path)?;
+        get_json(&folder_url).await
+    }
+}
+
+/// A repository on a Gitea (or Forgejo) instance, read through its v1 API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GiteaSource {
+    /// The root URL of the instance, e.g. `https://gitea.example.com`.
+    base_url: String,
+    owner: String,
+    repository: String,
+    branch: Option<String>,
+}
+
+impl GiteaSource {
+    fn get_api_url(&self, endpoint: &str, path: &str) -> String {
+        let ref_param = match self.branch {
+            Some(ref branch) => format!("?ref={}", encode_component(branch)),
+            None => "".to_string()
+        };
+
+        format!("{}/api/v1/repos/{}/{}/{}/{}{}", self.base_url.trim_end_matches('/'), self.owner, self.repository, endpoint, path, ref_param)
+    }
+}
+
+#[async_trait]
+impl PluginSource for GiteaSource {
+    fn id(&self) -> String {
+        format!("gitea:{}/{}/{}@{}", self.base_url.trim_end_matches('/'), self.owner, self.repository, self.branch.as_deref().unwrap_or_default())
+    }
+
+    fn kind(&self) -> PluginSourceKind {
+        PluginSourceKind::Gitea(self.clone())
+    }
+
+    fn get_file_url(&self, path: &str) -> Result<String, PluginError> {
+        Ok(self.get_api_url("raw", path))
+    }
+
+    async fn list_folder(&self, path: &str) -> Result<Vec<RemoteEntry>, PluginError> {
+        // The contents endpoint answers in the same shape as GitHub's.
+        get_json(&self.get_api_url("contents", path)).await
+    }
+}
+
+/// A project on a GitLab instance, read through its v4 API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GitLabSource {
+    /// The root URL of the instance, e.g. `https://gitlab.com`.
+    base_url: String,
+    /// The project path (`group/project`) or numeric id.
+    project: String,
+    branch: Option<String>,
+}
+
+impl GitLabSource {
+    fn get_project_url(&self) -> String {
+        format!("{}/api/v4/projects/{}", self.base_url.trim_end_matches('/'), encode_component(&self.project))
+    }
+
+    fn get_ref(&self) -> String {
+        encode_component(self.branch.as_deref().unwrap_or("HEAD"))
+    }
+}
+
+/// An entry of a GitLab repository tree.
+#[derive(Debug, Deserialize)]
+struct GitLabTreeEntry {
+    name: String,
+    /// Either `blob` or `tree`.
+    #[serde(rename = "type")]
+    entry_type: String,
+}
+
+#[async_trait]
+impl PluginSource for GitLabSource {
+    fn id(&self) -> String {
+        format!("gitlab:{}/{}@{}", self.base_url.trim_end_matches('/'), self.project, self.branch.as_deref().unwrap_or_default())
+    }
+
+    fn kind(&self) -> PluginSourceKind {
+        PluginSourceKind::Gitlab(self.clone())
+    }
+
+    fn get_file_url(&self, path: &str) -> Result<String, PluginError> {
+        Ok(format!("{}/repository/files/{}/raw?ref={}", self.get_project_url(), encode_component(path), self.get_ref()))
+    }
+
+    async fn list_folder(&self, path: &str) -> Result<Vec<RemoteEntry>, PluginError> {
+        let tree_url = format!("{}/repository/tree?path={}&ref={}&per_page=100", self.get_project_url(), encode_component(path), self.get_ref());
+        let tree: Vec<GitLabTreeEntry> = get_json(&tree_url).await?;
+
+        let entries = tree.into_iter().map(|entry| RemoteEntry {
+            name: entry.name,
+            entry_type: match entry.entry_type.as_str() {
+                "tree" => "dir".to_string(),
+                "blob" => "file".to_string(),
+                other => other.to_string(),
+            },
+        }).collect();
+
+        Ok(entries)
+    }
+}
+
+/// A plain HTTP server hosting the plugin files.
+/// Since folders cannot be listed over plain HTTP, the server must publish a registry `index.json` at its root.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpIndexSource {
+    /// The URL the `index.json` and the `plugins` folder are relative to.
+    base_url: String,
+}
+
+#[async_trait]
+impl PluginSource for HttpIndexSource {
+    fn id(&self) -> String {
+        format!("http:{}", self.base_url.trim_end_matches('/'))
+    }
+
+    fn kind(&self) -> PluginSourceKind {
+        PluginSourceKind::Http(self.clone())
+    }
+
+    fn get_file_url(&self, path: &str) -> Result<String, PluginError> {
+        Ok(format!("{}/{}", self.base_url.trim_end_matches('/'), path))
+    }
+
+    async fn list_folder(&self, path: &str) -> Result<Vec<RemoteEntry>, PluginError> {
+        let index: RegistryIndex = get_json(&self.get_file_url("index.json")?).await?;
+        let files = index.plugins.iter().flat_map(|plugin| {
+            plugin.files.iter().map(move |file| format!("plugins/{}/{}", plugin.folder, file.path))
+        });
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let mut entries: Vec<RemoteEntry> = Vec::new();
+
+        for file in files {
+            let relative_path = match file.strip_prefix(&prefix) {
+                Some(relative_path) => relative_path,
+                None => continue,
+            };
+
+            // Only the direct children of the folder are listed, deeper files show up as their top folder.
+            let (name, entry_type) = match relative_path.split_once('/') {
+                Some((folder, _)) => (folder, "dir"),
+                None => (relative_path, "file"),
+            };
+
+            if name.is_empty() || entries.iter().any(|entry| entry.name == name) {
+                continue;
+            }
+
+            entries.push(RemoteEntry {
+                name: name.to_string(),
+                entry_type: entry_type.to_string(),
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+/// A folder on the local filesystem or a mounted file share.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LocalSource {
+    path: String,
+}
+
+impl LocalSource {
+    /**
+     * The location of a path of the source on disk. Paths that would lead outside of the source folder are refused.
+     */
+    fn resolve(&self, path: &str) -> Result<path::PathBuf, PluginError> {
+        Ok(path::Path::new(&self.path).join(paths::sanitize_relative_path(path)?))
+    }
+}
+
+#[async_trait]
+impl PluginSource for LocalSource {
+    fn id(&self) -> String {
+        format!("local:{}", self.path)
+    }
+
+    fn kind(&self) -> PluginSourceKind {
+        PluginSourceKind::Local(self.clone())
+    }
+
+    fn get_file_url(&self, path: &str) -> Result<String, PluginError> {
+        match self.resolve(path)?.to_str() {
+            Some(file_path) => Ok(file_path.to_string()),
+            None => Err(PluginError::Io {
+                message: format!("Error reading path of {}.", path),
+            }),
+        }
+    }
+
+    async fn list_folder(&self, path: &str) -> Result<Vec<RemoteEntry>, PluginError> {
+        let folder_path = self.resolve(path)?;
+        let folder_contents = match fs::read_dir(&folder_path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                return Err(PluginError::Io {
+                    message: format!("Error reading folder {}: {}", folder_path.display(), err),
+                });
+            }
+        };
+
+        let mut entries: Vec<RemoteEntry> = Vec::new();
+
+        for entry in folder_contents {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    println!("Error reading folder entry: {}", err);
+                    continue;
+                }
+            };
+
+            let name = match entry.file_name().to_str() {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+
+            let entry_type = if entry.path().is_dir() { "dir" } else { "file" };
+
+            entries.push(RemoteEntry {
+                name,
+                entry_type: entry_type.to_string(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    async fn fetch_file(&self, path: &str) -> Result<String, PluginError> {
+        let file_path = self.get_file_url(path)?;
+
+        match fs::read_to_string(&file_path) {
+            Ok(contents) => Ok(contents),
+            Err(err) => Err(PluginError::Io {
+                message: format!("Error reading file {}: {}", file_path, err),
+            }),
+        }
+    }
+}
+
+/**
+ * Downloads a text file over HTTP, treating error statuses as failures.
+ */
+pub async fn get_text(url: &str) -> Result<String, PluginError> {
+    // GitHub refuses API requests without a user agent.
+    let response = match reqwest::Client::new().get(url).header("User-Agent", "Mozilla/5.0").send().await {
+        Ok(response) => response,
+        Err(err) => {
+            return Err(PluginError::Network {
+                message: format!("Error getting {}: {}", url, err),
+            });
+        }
+    };
+
+    if !response.status().is_success() {
+        return Err(PluginError::Network {
+            message: format!("Error getting {}: server responded with {}", url, response.status()),
+        });
+    }
+
+    match response.text().await {
+        Ok(contents) => Ok(contents),
+        Err(err) => Err(PluginError::Network {
+            message: format!("Error reading {}: {}", url, err),
+        }),
+    }
+}
+
+/**
+ * Downloads and parses a JSON document over HTTP.
+ */
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, PluginError> {
+    let contents = get_text(url).await?;
+
+    match serde_json::from_str(&contents) {
+        Ok(contents) => Ok(contents),
+        Err(err) => Err(PluginError::SourceInvalid {
+            message: format!("Error parsing {} to JSON: {}", url, err),
+        }),
+    }
+}
+
+/**
+ * Percent-encodes a value so it can be used as a single URL path segment or query value.
+ */
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_source_stays_inside_its_folder() {
+        let source = LocalSource { path: "/srv/raphael".to_string() };
+
+        assert_eq!(source.get_file_url("plugins/example/info.json").unwrap(), "/srv/raphael/plugins/example/info.json");
+        for escaping in ["../secret.json", "plugins/../../secret.json", "/etc/passwd", "plugins/example/../../../secret.json"] {
+            assert!(matches!(source.get_file_url(escaping), Err(PluginError::UnsafePath { .. })), "{}", escaping);
+        }
+    }
+}
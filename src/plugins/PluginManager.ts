@@ -1,4 +1,6 @@
 import { invoke } from "@tauri-apps/api";
+import { ask } from "@tauri-apps/api/dialog";
+import { listen, UnlistenFn } from "@tauri-apps/api/event";
 import { recursiveToCamel } from "../utils/toCamelCase";
 
 export default class PluginManager {
@@ -8,11 +10,15 @@ export default class PluginManager {
         // this.loadPlugins();
     }
 
-    async loadPlugins() {
+    /**
+     * Gets the installed plugins and runs their startup scripts.
+     * Plugins are read from disk once when the app starts. Pass `reimport` to read them again, e.g. after editing one by hand.
+     */
+    async loadPlugins(reimport: boolean = false) {
         let plugins: RaphaelPlugin[] = [];
 
-        await invoke("import_plugins").then((result: unknown) => {
-            const parsedResult = recursiveToCamel(JSON.parse(result as string));
+        await invoke(reimport ? "import_plugins" : "list_plugins").then((result: unknown) => {
+            const parsedResult = (JSON.parse(result as string) as Record<string, unknown>[]).map(toCamelKeepingFunctions);
 
             plugins = parsedResult as RaphaelPlugin[];
             this.plugins = plugins;
@@ -26,20 +32,327 @@ export default class PluginManager {
         }
     }
 
+    async fetchCatalog(): Promise<RaphaelCatalogEntry[]> {
+        let catalog: RaphaelCatalogEntry[] = [];
+
+        await invoke("list_available_plugins").then((result: unknown) => {
+            catalog = (JSON.parse(result as string) as Record<string, unknown>[]).map(toCamelKeepingFunctions) as RaphaelCatalogEntry[];
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return catalog;
+    }
+
+    /**
+     * Installs a plugin and asks the user to approve its permissions. The plugin is uninstalled again if they refuse.
+     */
+    async installPlugin(source: string, pluginId: string) {
+        await invoke("install_plugin", { source: source, pluginId: pluginId }).then(async (result: unknown) => {
+            const plugin = toCamelKeepingFunctions(JSON.parse(result as string)) as RaphaelPlugin;
+            this.plugins.push(plugin);
+
+            if (!await this.approvePermissions(plugin)) {
+                await this.uninstallPlugin(plugin.id);
+                return;
+            }
+
+            this.executeStartupScript(plugin.id);
+        }).catch((error) => {
+            console.error(error);
+        });
+    }
+
+    async uninstallPlugin(pluginId: string) {
+        await invoke("uninstall_plugin", { pluginId: pluginId }).then(() => {
+            this.plugins = this.plugins.filter((plugin) => plugin.id !== pluginId);
+        }).catch((error) => {
+            console.error(error);
+        });
+    }
+
+    async checkPluginUpdates(): Promise<RaphaelPluginUpdate[]> {
+        let updates: RaphaelPluginUpdate[] = [];
+
+        await invoke("check_plugin_updates").then((result: unknown) => {
+            updates = recursiveToCamel(JSON.parse(result as string)) as RaphaelPluginUpdate[];
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return updates;
+    }
+
+    async updatePlugin(pluginId: string) {
+        await invoke("update_plugin", { pluginId: pluginId }).then((result: unknown) => {
+            const plugin = toCamelKeepingFunctions(JSON.parse(result as string)) as RaphaelPlugin;
+
+            this.plugins = this.plugins.map((installed) => installed.id === plugin.id ? plugin : installed);
+
+            // Only asks if the new version declares more than was approved. The plugin won't run until it is approved.
+            return this.approvePermissions(plugin);
+        }).catch((error) => {
+            console.error(error);
+        });
+    }
+
+    /**
+     * Asks the user to approve the permissions of a plugin they have not approved yet.
+     * Returns whether the plugin may run, which is also the case if there was nothing left to approve.
+     */
+    async approvePermissions(plugin: RaphaelPlugin): Promise<boolean> {
+        let missing: RaphaelPluginPermissions | undefined;
+        // Sent back as it came when granting, so that only what the user saw gets granted.
+        let shownPermissions: unknown;
+
+        await invoke("get_missing_plugin_permissions", { pluginId: plugin.id }).then((result: unknown) => {
+            shownPermissions = JSON.parse(result as string);
+            missing = recursiveToCamel(shownPermissions) as RaphaelPluginPermissions;
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        if (!missing) {
+            return false;
+        }
+
+        const descriptions = describePermissions(missing);
+        if (descriptions.length === 0) {
+            return true;
+        }
+
+        const approved = await ask(`${plugin.name} wants to:\n\n${descriptions.map((description) => `- ${description}`).join("\n")}\n\nAllow it?`, { title: plugin.name, type: "warning" });
+        if (!approved) {
+            return false;
+        }
+
+        let granted = false;
+        await invoke("grant_plugin_permissions", { pluginId: plugin.id, permissions: shownPermissions }).then(() => {
+            granted = true;
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return granted;
+    }
+
+    /**
+     * Shows the user the shell scripts of a plugin they have not trusted yet and asks them to trust them.
+     * Resolves to true if the plugin's shell scripts can run.
+     */
+    async trustShellScripts(pluginId: string): Promise<boolean> {
+        let untrusted: RaphaelShellScript[] | undefined;
+
+        await invoke("get_untrusted_shell_scripts", { pluginId: pluginId }).then((result: unknown) => {
+            untrusted = recursiveToCamel(JSON.parse(result as string)) as RaphaelShellScript[];
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        if (!untrusted) {
+            return false;
+        }
+
+        if (untrusted.length === 0) {
+            return true;
+        }
+
+        const pluginName = this.plugins.find((plugin) => plugin.id === pluginId)?.name ?? pluginId;
+        const scripts = untrusted.map((script) => `${script.path}:\n\n${script.script}`).join("\n\n");
+
+        const trusted = await ask(`${pluginName} wants to run these shell scripts:\n\n${scripts}\n\nOnly run them if you trust what they do. Run them?`, { title: pluginName, type: "warning" });
+        if (!trusted) {
+            return false;
+        }
+
+        let saved = false;
+        await invoke("trust_shell_scripts", { pluginId: pluginId, hashes: untrusted.map((script) => script.hash) }).then(() => {
+            saved = true;
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return saved;
+    }
+
+    async listSources(): Promise<RaphaelPluginSource[]> {
+        let sources: RaphaelPluginSource[] = [];
+
+        await invoke("list_plugin_sources").then((result: unknown) => {
+            sources = recursiveToCamel(JSON.parse(result as string)) as RaphaelPluginSource[];
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return sources;
+    }
+
+    /**
+     * Adds a plugin source. The source is sent as-is, so its keys must be in snake case like the backend expects.
+     * For example: `{ kind: "gitea", base_url: "https://gitea.example.com", owner: "team", repository: "plugins", branch: "main" }`.
+     * When trusted keys (base64 ed25519 public keys) are given, only plugins signed with one of them can be installed from the source.
+     */
+    async addSource(source: Record<string, unknown>, trustedKeys?: string[]): Promise<string | undefined> {
+        let sourceId: string | undefined;
+
+        await invoke("add_plugin_source", { source: source, trustedKeys: trustedKeys }).then((result: unknown) => {
+            sourceId = result as string;
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return sourceId;
+    }
+
+    async removeSource(sourceId: string) {
+        await invoke("remove_plugin_source", { sourceId: sourceId }).catch((error) => {
+            console.error(error);
+        });
+    }
+
+    async reorderSources(sourceIds: string[]) {
+        await invoke("reorder_plugin_sources", { sourceIds: sourceIds }).catch((error) => {
+            console.error(error);
+        });
+    }
+
+    async setSourceEnabled(sourceId: string, enabled: boolean) {
+        await invoke("set_plugin_source_enabled", { sourceId: sourceId, enabled: enabled }).catch((error) => {
+            console.error(error);
+        });
+    }
+
     async executeStartupScript(pluginId: string) {
-        invoke("execute_startup_script", { pluginId: pluginId }).then((result) => {
-            console.log(result);
+        if (!await this.trustShellScripts(pluginId)) {
+            return;
+        }
+
+        invoke("execute_startup_script", { pluginId: pluginId }).then((executionId: unknown) => {
+            return this.waitForExecution(executionId as string);
+        }).then((executionResult) => {
+            console.log(executionResult?.stdout);
         }).catch((error) => {
             console.error(error);
         });
     }
 
-    async executeFunctionScript(pluginId: string, scriptPath: string, args?: string[]) {
-        await invoke("execute_function_script", { pluginId: pluginId, scriptPath: scriptPath, args: Array.from(args ?? []) }).then((result: unknown) => {
-            console.log(result);
+    /**
+     * Runs a function script by its path and waits for its result. Prefer `executeFunction`, which doesn't depend on where the plugin is installed.
+     * `onStart` receives the execution id as soon as the script starts, which can be passed to `cancelExecution`.
+     * `timeout` is in seconds and overrides the plugin's own.
+     * `args` can be any JSON. Plugins using the "argv" calling convention get anything but strings as JSON text.
+     */
+    async executeFunctionScript(pluginId: string, scriptPath: string, args?: unknown[], onStart?: (executionId: string) => void, timeout?: number): Promise<RaphaelExecutionResult | undefined> {
+        let executionResult: RaphaelExecutionResult | undefined;
+
+        if (!await this.trustShellScripts(pluginId)) {
+            return executionResult;
+        }
+
+        await invoke("execute_function_script", { pluginId: pluginId, scriptPath: scriptPath, args: Array.from(args ?? []), timeout: timeout }).then((executionId: unknown) => {
+            onStart?.(executionId as string);
+            return this.waitForExecution(executionId as string);
+        }).then((result) => {
+            executionResult = result;
+            console.log(executionResult?.stdout);
         }).catch((error) => {
             console.error(error);
         });
+
+        return executionResult;
+    }
+
+    /**
+     * Lists the functions of every installed plugin, with the ids to call them by.
+     */
+    async listFunctions(): Promise<RaphaelFunctionListing[]> {
+        let functions: RaphaelFunctionListing[] = [];
+
+        await invoke("list_functions").then((result: unknown) => {
+            // The schemas of the functions are the plugins' own, so their keys are left as they are.
+            functions = (JSON.parse(result as string) as Record<string, unknown>[]).map((listing) => ({
+                id: listing.id,
+                pluginId: listing.plugin_id,
+                pluginName: listing.plugin_name,
+                function: listing.function,
+            })) as RaphaelFunctionListing[];
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return functions;
+    }
+
+    /**
+     * Runs a function by its full id, e.g. "3f1c...:summarize", and waits for its result.
+     * `args` are checked against the function's parameters before it runs. See `executeFunctionScript` for the rest.
+     */
+    async executeFunction(functionId: string, args?: unknown[], onStart?: (executionId: string) => void, timeout?: number): Promise<RaphaelExecutionResult | undefined> {
+        let executionResult: RaphaelExecutionResult | undefined;
+
+        const pluginId = functionId.split(":")[0];
+        if (!await this.trustShellScripts(pluginId)) {
+            return executionResult;
+        }
+
+        await invoke("execute_function", { functionId: functionId, args: Array.from(args ?? []), timeout: timeout }).then((executionId: unknown) => {
+            onStart?.(executionId as string);
+            return this.waitForExecution(executionId as string);
+        }).then((result) => {
+            executionResult = result;
+            console.log(executionResult?.stdout);
+        }).catch((error) => {
+            console.error(error);
+        });
+
+        return executionResult;
+    }
+
+    async waitForExecution(executionId: string): Promise<RaphaelExecutionResult> {
+        const result = JSON.parse(await invoke("wait_for_execution", { executionId: executionId }) as string);
+        // The output is the plugin's own data, so its keys are left as they are.
+        return { ...recursiveToCamel({ ...result, output: null }) as object, output: result.output } as RaphaelExecutionResult;
+    }
+
+    async cancelExecution(executionId: string) {
+        await invoke("cancel_execution", { executionId: executionId }).catch((error) => {
+            console.error(error);
+        });
+    }
+
+    /**
+     * Calls `callback` with every line printed by any running plugin script, as it is printed.
+     */
+    async onOutput(callback: (output: RaphaelPluginOutput) => void): Promise<UnlistenFn> {
+        return listen("plugin-output", (event) => {
+            callback(recursiveToCamel(event.payload) as RaphaelPluginOutput);
+        });
+    }
+
+    /**
+     * Calls `callback` whenever a plugin script finishes, successfully or not.
+     */
+    async onExecutionFinished(callback: (finished: RaphaelExecutionFinished) => void): Promise<UnlistenFn> {
+        return listen("plugin-execution-finished", (event) => {
+            const payload = event.payload as { plugin_id: string; execution_id: string; result: unknown; error: RaphaelPluginError | null };
+
+            // The error is kept in snake case like everywhere else.
+            callback({
+                pluginId: payload.plugin_id,
+                executionId: payload.execution_id,
+                result: payload.result ? recursiveToCamel(payload.result) as RaphaelExecutionResult : null,
+                error: payload.error,
+            });
+        });
+    }
+
+    /**
+     * Calls `callback` whenever a plugin script asks for a notification to be shown through the host API.
+     */
+    async onNotification(callback: (notification: RaphaelPluginNotification) => void): Promise<UnlistenFn> {
+        return listen("plugin-notification", (event) => {
+            callback(recursiveToCamel(event.payload) as RaphaelPluginNotification);
+        });
     }
 }
 
@@ -47,12 +360,90 @@ type RaphaelPlugin = {
     id: string;
     name: string;
     version?: string;
+    description?: string;
 
     localPath?: string;
     remoteUrl?: string;
 
     startupScript?: RaphaelPluginScript;
     functionScripts?: RaphaelPluginScript[];
+    functions?: RaphaelPluginFunction[];
+    // How long the plugin's scripts may run, in seconds.
+    timeout?: number;
+    // The script that runs as the plugin's daemon. Function calls are sent to it instead of starting a script.
+    daemon?: string;
+    permissions?: RaphaelPluginPermissions;
+    // Overrides the default resource limits from the app settings.
+    limits?: RaphaelResourceLimits;
+    // How scripts get their arguments: on the command line, or as a JSON envelope on stdin.
+    callingConvention?: "argv" | "json";
+}
+
+type RaphaelResourceLimits = {
+    // In megabytes.
+    memory?: number;
+    // In seconds.
+    cpuTime?: number;
+    openFiles?: number;
+    processes?: number;
+    // In bytes, for each of stdout and stderr.
+    output?: number;
+}
+
+type RaphaelPluginPermissions = {
+    filesystem: { read: string[]; write: string[] };
+    // The hosts the plugin may connect to. "*" is any host.
+    network: string[];
+    subprocess: boolean;
+    shell: boolean;
+    // The host API methods the plugin may call, e.g. "notifications.show".
+    hostApi: string[];
+}
+
+/**
+ * Describes permissions in words the user can approve.
+ */
+function describePermissions(permissions: RaphaelPluginPermissions): string[] {
+    return [
+        ...permissions.filesystem.read.map((path) => `Read files in ${path}`),
+        ...permissions.filesystem.write.map((path) => `Read and write files in ${path}`),
+        ...permissions.network.map((host) => host === "*" ? "Connect to any server" : `Connect to ${host}`),
+        ...(permissions.subprocess ? ["Start other programs"] : []),
+        ...(permissions.shell ? ["Run shell scripts"] : []),
+        ...permissions.hostApi.map((method) => `Use ${method}`),
+    ];
+}
+
+type RaphaelPluginFunction = {
+    id: string;
+    // The script in the plugin's scripts folder that runs the function.
+    entry: string;
+    description?: string;
+    // JSON Schemas, kept as the plugin wrote them.
+    parameters?: unknown;
+    result?: unknown;
+}
+
+type RaphaelFunctionListing = {
+    // The full id of the function, e.g. "3f1c...:summarize".
+    id: string;
+    pluginId: string;
+    pluginName: string;
+    function: RaphaelPluginFunction;
+}
+
+/**
+ * Converts a plugin or catalog entry to camel case, except for its functions, whose schemas are the plugin's own.
+ */
+function toCamelKeepingFunctions(item: Record<string, unknown>): unknown {
+    return { ...recursiveToCamel({ ...item, functions: null }) as object, functions: item.functions };
+}
+
+type RaphaelShellScript = {
+    path: string;
+    script: string;
+    // The SHA-256 hash of the script, which is what gets trusted.
+    hash: string;
 }
 
 type RaphaelPluginScript = {
@@ -61,4 +452,86 @@ type RaphaelPluginScript = {
     engine?: string;
 }
 
-export type { RaphaelPlugin, RaphaelPluginScript };
+type RaphaelCatalogEntry = {
+    id: string;
+    name: string;
+    version?: string;
+    description?: string;
+
+    source: string;
+    folder: string;
+    functions: RaphaelPluginFunction[];
+    installed: boolean;
+}
+
+type RaphaelPluginUpdate = {
+    id: string;
+    name: string;
+    source: string;
+    installedVersion: string;
+    availableVersion: string;
+}
+
+type RaphaelPluginSource = {
+    id: string;
+    enabled: boolean;
+    trustedKeys: string[];
+    kind: "github" | "gitea" | "gitlab" | "http" | "local";
+
+    // The remaining keys depend on the kind of the source.
+    [key: string]: unknown;
+}
+
+type RaphaelExecutionResult = {
+    stdout: string;
+    stderr: string;
+    exitCode: number | null;
+    // In milliseconds.
+    duration: number;
+    // Whether stdout or stderr was cut off because the script printed too much.
+    truncated: boolean;
+    // What the script returned in its result envelope, for plugins using the "json" calling convention.
+    output: unknown;
+}
+
+type RaphaelPluginOutput = {
+    pluginId: string;
+    executionId: string;
+    stream: "stdout" | "stderr";
+    line: string;
+}
+
+type RaphaelExecutionFinished = {
+    pluginId: string;
+    executionId: string;
+    // Exactly one of these is set.
+    result: RaphaelExecutionResult | null;
+    error: RaphaelPluginError | null;
+}
+
+type RaphaelPluginNotification = {
+    pluginId: string;
+    pluginName: string;
+    title: string;
+    body: string;
+}
+
+/**
+ * The error every plugin command rejects with. Its keys are in snake case, as sent by the backend.
+ */
+type RaphaelPluginError =
+    | { kind: "NotFound" | "Conflict" | "ManifestInvalid" | "SourceInvalid" | "Network" | "Io"; message: string }
+    | { kind: "VerificationFailed" | "UnsafePath" | "PermissionDenied" | "InvalidInput"; message: string }
+    | { kind: "EngineUnsupported"; engine: string; message: string }
+    | { kind: "EngineMissing"; engine: string }
+    | { kind: "ScriptFailed"; exit_code: number | null; stderr: string }
+    | { kind: "ScriptError"; message: string }
+    | { kind: "TimedOut"; timeout: number }
+    | { kind: "LimitExceeded"; limit: "memory" | "cpu_time" | "open_files" | "processes"; message: string }
+    | { kind: "Cancelled" }
+    | { kind: "RpcError"; code: number; message: string }
+    | { kind: "DaemonUnavailable"; message: string }
+    | { kind: "PermissionsNotGranted"; plugin_id: string; missing: { filesystem: { read: string[]; write: string[] }; network: string[]; subprocess: boolean; shell: boolean; host_api: string[] } }
+    | { kind: "ShellScriptsNotTrusted"; plugin_id: string; paths: string[] };
+
+export type { RaphaelPlugin, RaphaelPluginPermissions, RaphaelResourceLimits, RaphaelPluginScript, RaphaelCatalogEntry, RaphaelPluginUpdate, RaphaelPluginSource, RaphaelExecutionResult, RaphaelPluginOutput, RaphaelExecutionFinished, RaphaelPluginNotification, RaphaelPluginError };